@@ -63,6 +63,14 @@
 //! # Ok::<(), Error>(())
 //! ```
 //!
+//! ## io safety
+//! When the `io_safety` feature is enabled, `OwnedHandle` and
+//! `FileDescriptor` can be converted to and from the standard library
+//! `OwnedFd` on unix systems and `OwnedHandle` on Windows, and implement
+//! `AsFd` and `AsHandle` respectively.  On Windows, converting to the
+//! standard library type fails if the handle is invalid.
+//! This feature requires Rust 1.63 or later.
+//!
 //! ## Polling
 //! The `mio` crate offers powerful and scalable IO multiplexing, but there
 //! are some situations where `mio` doesn't fit.  The `filedescriptor` crate
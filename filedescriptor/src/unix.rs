@@ -84,6 +84,64 @@ impl FromRawFd for OwnedHandle {
     }
 }
 
+#[cfg(feature = "io_safety")]
+mod io_safety {
+    use super::*;
+    use std::os::unix::io::{AsFd, BorrowedFd, OwnedFd};
+
+    impl From<OwnedFd> for OwnedHandle {
+        fn from(fd: OwnedFd) -> Self {
+            unsafe { Self::from_raw_fd(fd.into_raw_fd()) }
+        }
+    }
+
+    impl From<OwnedHandle> for OwnedFd {
+        fn from(handle: OwnedHandle) -> Self {
+            unsafe { Self::from_raw_fd(handle.into_raw_fd()) }
+        }
+    }
+
+    impl AsFd for OwnedHandle {
+        fn as_fd(&self) -> BorrowedFd<'_> {
+            unsafe { BorrowedFd::borrow_raw(self.handle) }
+        }
+    }
+
+    impl From<OwnedFd> for FileDescriptor {
+        fn from(fd: OwnedFd) -> Self {
+            Self { handle: fd.into() }
+        }
+    }
+
+    impl From<FileDescriptor> for OwnedFd {
+        fn from(fd: FileDescriptor) -> Self {
+            fd.handle.into()
+        }
+    }
+
+    impl AsFd for FileDescriptor {
+        fn as_fd(&self) -> BorrowedFd<'_> {
+            self.handle.as_fd()
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn round_trip() {
+            let pipe = Pipe::new().unwrap();
+            let raw = pipe.read.as_raw_fd();
+            let owned: OwnedFd = pipe.read.into();
+            assert_eq!(owned.as_raw_fd(), raw);
+            let fd: FileDescriptor = owned.into();
+            assert_eq!(fd.as_raw_fd(), raw);
+            assert_eq!(fd.as_fd().as_raw_fd(), raw);
+        }
+    }
+}
+
 impl OwnedHandle {
     /// Helper function to set the close-on-exec flag for a raw descriptor
     fn cloexec(&mut self) -> Result<()> {
@@ -224,6 +224,80 @@ impl IntoRawHandle for OwnedHandle {
     }
 }
 
+#[cfg(feature = "io_safety")]
+mod io_safety {
+    use super::*;
+    use std::convert::TryFrom;
+
+    type StdOwnedHandle = std::os::windows::io::OwnedHandle;
+
+    impl From<StdOwnedHandle> for OwnedHandle {
+        fn from(handle: StdOwnedHandle) -> Self {
+            unsafe { Self::from_raw_handle(handle.into_raw_handle()) }
+        }
+    }
+
+    /// Fails if the handle is null or INVALID_HANDLE_VALUE, which our
+    /// OwnedHandle permits but the std type does not.
+    impl TryFrom<OwnedHandle> for StdOwnedHandle {
+        type Error = IoError;
+
+        fn try_from(handle: OwnedHandle) -> std::result::Result<Self, IoError> {
+            if handle.handle.is_null() || handle.handle == INVALID_HANDLE_VALUE as _ {
+                return Err(IoError::new(
+                    io::ErrorKind::InvalidInput,
+                    "cannot convert an invalid handle into an OwnedHandle",
+                ));
+            }
+            Ok(unsafe { Self::from_raw_handle(handle.into_raw_handle()) })
+        }
+    }
+
+    impl AsHandle for OwnedHandle {
+        fn as_handle(&self) -> BorrowedHandle<'_> {
+            unsafe { BorrowedHandle::borrow_raw(self.handle) }
+        }
+    }
+
+    impl From<StdOwnedHandle> for FileDescriptor {
+        fn from(handle: StdOwnedHandle) -> Self {
+            Self {
+                handle: handle.into(),
+            }
+        }
+    }
+
+    impl TryFrom<FileDescriptor> for StdOwnedHandle {
+        type Error = IoError;
+
+        fn try_from(fd: FileDescriptor) -> std::result::Result<Self, IoError> {
+            Self::try_from(fd.handle)
+        }
+    }
+
+    impl AsHandle for FileDescriptor {
+        fn as_handle(&self) -> BorrowedHandle<'_> {
+            self.handle.as_handle()
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn round_trip() {
+            let pipe = Pipe::new().unwrap();
+            let raw = pipe.read.as_raw_handle();
+            let owned = StdOwnedHandle::try_from(pipe.read).unwrap();
+            assert_eq!(owned.as_raw_handle(), raw);
+            let fd: FileDescriptor = owned.into();
+            assert_eq!(fd.as_raw_handle(), raw);
+            assert_eq!(fd.as_handle().as_raw_handle(), raw);
+        }
+    }
+}
+
 impl FileDescriptor {
     #[inline]
     pub(crate) fn as_stdio_impl(&self) -> Result<std::process::Stdio> {
@@ -405,6 +479,18 @@ impl io::Write for FileDescriptor {
 
 impl Pipe {
     pub fn new() -> Result<Pipe> {
+        Self::with_buffer_size(0)
+    }
+
+    /// Create a pipe, suggesting `size` bytes for its buffer.
+    /// The size is advisory: the system may round it up or down.
+    /// A larger buffer lets a writer emit a big burst of output
+    /// without blocking until the reader catches up.
+    /// A size of 0 selects the system default.
+    pub fn with_buffer_size(size: u32) -> Result<Pipe> {
+        // Neither end is inheritable; a handle that needs to be passed
+        // to a child must be explicitly duplicated or passed to an API
+        // such as CreatePseudoConsole that takes the handle directly.
         let mut sa = SECURITY_ATTRIBUTES {
             nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
             lpSecurityDescriptor: ptr::null_mut(),
@@ -412,7 +498,7 @@ impl Pipe {
         };
         let mut read: HANDLE = INVALID_HANDLE_VALUE as _;
         let mut write: HANDLE = INVALID_HANDLE_VALUE as _;
-        if unsafe { CreatePipe(&mut read, &mut write, &mut sa, 0) } == 0 {
+        if unsafe { CreatePipe(&mut read, &mut write, &mut sa, size) } == 0 {
             Err(Error::Pipe(IoError::last_os_error()))
         } else {
             Ok(Pipe {
@@ -558,6 +644,9 @@ pub fn poll_impl(pfd: &mut [pollfd], duration: Option<Duration>) -> Result<usize
 #[cfg(test)]
 mod test {
     use std::io::{Read, Write};
+    use std::os::windows::prelude::*;
+    use winapi::um::handleapi::GetHandleInformation;
+    use winapi::um::winbase::HANDLE_FLAG_INHERIT;
 
     #[test]
     fn socketpair() {
@@ -567,4 +656,30 @@ mod test {
         assert_eq!(b.read(&mut buf).unwrap(), 5);
         assert_eq!(&buf, b"hello");
     }
+
+    #[test]
+    fn pipe_with_buffer_size() {
+        let mut pipe = crate::Pipe::with_buffer_size(64 * 1024).unwrap();
+        let data = vec![0x55u8; 32 * 1024];
+        // Would block forever without a reader if the buffer
+        // were the default size
+        pipe.write.write_all(&data).unwrap();
+        drop(pipe.write);
+        let mut buf = vec![];
+        pipe.read.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn pipe_is_not_inheritable() {
+        let pipe = crate::Pipe::new().unwrap();
+        for fd in &[&pipe.read, &pipe.write] {
+            let mut flags = 0;
+            assert_ne!(
+                unsafe { GetHandleInformation(fd.as_raw_handle() as _, &mut flags) },
+                0
+            );
+            assert_eq!(flags & HANDLE_FLAG_INHERIT, 0);
+        }
+    }
 }
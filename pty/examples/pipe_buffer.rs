@@ -0,0 +1,76 @@
+//! Compares the throughput of reading the output of `type` of a large
+//! file through a conpty with the default pipe buffers, against the
+//! same with larger buffers configured via
+//! `ConPtySystem::set_pipe_buffer_size`.
+//!
+//! Run it with `cargo run --release --example pipe_buffer`.
+//! This is only meaningful on Windows, as the buffer size is a conpty
+//! setting.
+
+#[cfg(windows)]
+fn main() {
+    use portable_pty::win::conpty::ConPtySystem;
+    use portable_pty::{CommandBuilder, PtySize, PtySystem};
+    use std::io::{Read, Write};
+    use std::time::Instant;
+
+    // 200000 lines of 80 characters
+    let path = std::env::temp_dir().join("portable-pty-pipe-buffer.txt");
+    {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&path).unwrap());
+        let line = format!("{}\r\n", "x".repeat(78));
+        for _ in 0..200_000 {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+    }
+
+    let measure = |label: &str, buffer_size: u32| {
+        let mut pty_system = ConPtySystem::default();
+        pty_system.set_pipe_buffer_size(buffer_size);
+        let pair = pty_system.openpty(PtySize::default()).unwrap();
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.arg("/c");
+        cmd.arg("type");
+        cmd.arg(&path);
+
+        let start = Instant::now();
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let mut reader = pair.master.try_clone_reader().unwrap();
+        // The reader only sees EOF once the pseudoconsole has been
+        // closed, so count the output on another thread while we wait
+        // for the child to exit
+        let counter = std::thread::spawn(move || {
+            let mut buf = [0u8; 8192];
+            let mut total = 0;
+            while let Ok(len) = reader.read(&mut buf) {
+                if len == 0 {
+                    break;
+                }
+                total += len;
+            }
+            total
+        });
+        child.wait().unwrap();
+        drop(pair);
+        let total = counter.join().unwrap();
+        let elapsed = start.elapsed().as_secs_f64();
+
+        println!(
+            "{}: {} bytes in {:.3}s, {:.2} MiB/s",
+            label,
+            total,
+            elapsed,
+            total as f64 / (1024. * 1024.) / elapsed
+        );
+    };
+
+    measure("default buffer", 0);
+    measure("1MiB buffer", 1024 * 1024);
+
+    std::fs::remove_file(&path).ok();
+}
+
+#[cfg(not(windows))]
+fn main() {
+    println!("The pipe buffer size is only configurable for conpty on Windows");
+}
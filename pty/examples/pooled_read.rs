@@ -0,0 +1,117 @@
+//! Compares the number of allocations made while reading the output of
+//! a program that produces a lot of output, between the usual approach
+//! of reading through `Box<dyn Read>` and copying each chunk into a new
+//! `Vec` to hand it on to another thread, and reading via
+//! `MasterPty::pooled_reader`.
+//!
+//! Run it with `cargo run --release --example pooled_read`.
+use portable_pty::{BufferPool, CommandBuilder, NativePtySystem, PtySize, PtySystem};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::time::Instant;
+
+/// Counts the allocations made by the whole program
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const CHUNK_SIZE: usize = 8192;
+
+fn output_command() -> CommandBuilder {
+    if cfg!(windows) {
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.args(["/c", "for /L %i in (1,1,20000) do @echo line %i of output"]);
+        cmd
+    } else {
+        let mut cmd = CommandBuilder::new("sh");
+        cmd.args(["-c", "seq 1 200000"]);
+        cmd
+    }
+}
+
+/// Runs the output command, passing each chunk that `read` produces to
+/// a consumer thread, and reports the allocations made while doing so
+fn measure<T>(label: &str, mut read: impl FnMut() -> Option<T>)
+where
+    T: std::ops::Deref<Target = [u8]> + Send + 'static,
+{
+    let (tx, rx) = sync_channel::<T>(4);
+    let consumer = std::thread::spawn(move || {
+        let mut total = 0;
+        for chunk in rx {
+            total += chunk.len();
+        }
+        total
+    });
+
+    let start_allocs = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    while let Some(chunk) = read() {
+        if tx.send(chunk).is_err() {
+            break;
+        }
+    }
+    drop(tx);
+    let total = consumer.join().unwrap();
+    let elapsed = start.elapsed().as_secs_f64();
+    let allocs = ALLOCATIONS.load(Ordering::Relaxed) - start_allocs;
+
+    println!(
+        "{}: {} bytes in {:.3}s, {} allocations, {:.0} allocations/s, {:.2} allocations/MiB",
+        label,
+        total,
+        elapsed,
+        allocs,
+        allocs as f64 / elapsed,
+        allocs as f64 / (total as f64 / (1024. * 1024.))
+    );
+}
+
+fn main() {
+    let pty_system = NativePtySystem::default();
+
+    // The usual loop: read into a stack buffer and copy each chunk
+    // into a new Vec to hand it on
+    {
+        let pair = pty_system.openpty(PtySize::default()).unwrap();
+        let mut child = pair.slave.spawn_command_once(output_command()).unwrap();
+        let mut reader = pair.master.try_clone_reader().unwrap();
+        drop(pair.master);
+
+        let mut buf = [0u8; CHUNK_SIZE];
+        measure("Box<dyn Read>", move || match reader.read(&mut buf) {
+            Ok(0) | Err(_) => None,
+            Ok(len) => Some(buf[..len].to_vec()),
+        });
+        child.wait().unwrap();
+    }
+
+    // The pooled reader: buffers go back to the pool once consumed
+    {
+        let pair = pty_system.openpty(PtySize::default()).unwrap();
+        let mut child = pair.slave.spawn_command_once(output_command()).unwrap();
+        let mut reader = pair
+            .master
+            .pooled_reader(BufferPool::new(CHUNK_SIZE, 8))
+            .unwrap();
+        drop(pair.master);
+
+        measure("pooled_reader", move || reader.read_chunk().ok().flatten());
+        child.wait().unwrap();
+    }
+}
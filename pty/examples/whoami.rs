@@ -17,10 +17,10 @@ fn main() {
         .unwrap();
 
     let cmd = CommandBuilder::new("whoami");
-    let mut child = pair.slave.spawn_command(cmd).unwrap();
-    // Release any handles owned by the slave: we don't need it now
-    // that we've spawned the child.
-    drop(pair.slave);
+    // We only spawn a single command, so let the slave be consumed;
+    // this releases any handles that it owned as soon as the child
+    // has been spawned.
+    let mut child = pair.slave.spawn_command_once(cmd).unwrap();
 
     let mut reader = pair.master.try_clone_reader().unwrap();
     // We hold handles on the pty.  Now that the child is complete
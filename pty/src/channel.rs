@@ -0,0 +1,112 @@
+//! Reading pty output on a background thread and handing it over via a
+//! bounded channel, so that a consumer that falls behind slows the child
+//! down rather than having its output accumulate in memory.
+use std::io::{ErrorKind, Read, Result as IoResult};
+use std::sync::mpsc::{sync_channel, Receiver};
+
+/// The maximum size of each chunk of output sent over the channel
+const CHUNK_SIZE: usize = 8192;
+
+/// Spawns a thread that reads from `reader` and sends the output over a
+/// channel that holds at most `capacity` chunks of up to 8KiB each.
+/// When the channel is full the thread stops reading until the receiver
+/// catches up; the pty buffer then fills and the child blocks in its
+/// writes, so runaway output, such as that of `yes`, can't exhaust
+/// memory.  At most `capacity + 1` chunks are buffered between the
+/// pty and the receiver.
+///
+/// A larger capacity absorbs bursts of output without stalling the
+/// child, at the cost of memory, and of the receiver lagging further
+/// behind the child when it can't keep up.  A capacity of zero hands
+/// each chunk over directly, so the child only makes progress while
+/// the receiver is waiting for output.
+///
+/// The channel is closed once the reader reaches EOF, after an error
+/// has been sent, or when the receiver is dropped; in the last case the
+/// thread only notices when the next chunk of output arrives.
+pub fn spawn_channel_reader<R: Read + Send + 'static>(
+    mut reader: R,
+    capacity: usize,
+) -> IoResult<Receiver<IoResult<Vec<u8>>>> {
+    let (tx, rx) = sync_channel(capacity);
+    std::thread::Builder::new()
+        .name("pty reader".to_string())
+        .spawn(move || {
+            let mut buf = vec![0u8; CHUNK_SIZE];
+            loop {
+                let chunk = match reader.read(&mut buf) {
+                    Ok(0) => return,
+                    Ok(len) => Ok(buf[..len].to_vec()),
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(err) => Err(err),
+                };
+                let failed = chunk.is_err();
+                if tx.send(chunk).is_err() || failed {
+                    return;
+                }
+            }
+        })?;
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    /// Produces output forever, counting the reads made from it
+    struct Flood(Arc<AtomicUsize>);
+
+    impl Read for Flood {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            buf.iter_mut().for_each(|b| *b = b'y');
+            Ok(buf.len())
+        }
+    }
+
+    /// Waits until the reader thread has stopped reading, which is when
+    /// it is blocked sending to a full channel, and returns the number
+    /// of reads that it made
+    fn settled_reads(reads: &AtomicUsize) -> usize {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut last = reads.load(Ordering::SeqCst);
+        loop {
+            std::thread::sleep(Duration::from_millis(50));
+            let current = reads.load(Ordering::SeqCst);
+            if current == last {
+                return current;
+            }
+            assert!(Instant::now() < deadline, "reader never stopped reading");
+            last = current;
+        }
+    }
+
+    #[test]
+    fn applies_back_pressure() {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let rx = spawn_channel_reader(Flood(Arc::clone(&reads)), 4).unwrap();
+        // 4 chunks in the channel, plus the one waiting to be sent
+        let count = settled_reads(&reads);
+        assert!(count <= 5, "made {} reads", count);
+
+        for _ in 0..10 {
+            assert_eq!(rx.recv().unwrap().unwrap().len(), CHUNK_SIZE);
+        }
+        let count = settled_reads(&reads);
+        assert!((10..=15).contains(&count), "made {} reads", count);
+    }
+
+    #[test]
+    fn closes_at_eof() {
+        let data: Vec<u8> = (0..20000u32).map(|i| i as u8).collect();
+        let rx = spawn_channel_reader(std::io::Cursor::new(data.clone()), 1).unwrap();
+        let mut output = vec![];
+        for chunk in rx {
+            output.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(output, data);
+    }
+}
@@ -146,52 +146,180 @@ fn get_base_env() -> BTreeMap<OsString, EnvEntry> {
     env
 }
 
+/// Specifies what one of the standard streams of a spawned command
+/// is connected to; see `CommandBuilder::stdin`, `CommandBuilder::stdout`
+/// and `CommandBuilder::stderr`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+pub enum StdioMode {
+    /// Connect the stream to the pty.  This is the default.
+    Pty,
+    /// Connect the stream to the corresponding stream of the
+    /// current process.
+    Inherit,
+    /// Connect the stream to the null device.
+    Null,
+    /// Connect the stream to a pipe whose read end is available from
+    /// `Child::take_stderr`.  This is only supported for stderr.
+    Pipe,
+}
+
+/// The scheduling priority of a spawned command; see
+/// `CommandBuilder::priority`.
+/// On Windows these map to the priority classes of the same name.
+/// On unix they map to nice values of 19, 10, 0, -5 and -10
+/// respectively; raising the priority above normal requires
+/// privileges that most processes don't have, and spawning fails if
+/// they are missing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+pub enum PriorityClass {
+    Idle,
+    BelowNormal,
+    Normal,
+    AboveNormal,
+    High,
+}
+
+impl PriorityClass {
+    #[cfg(unix)]
+    pub(crate) fn nice_value(self) -> libc::c_int {
+        match self {
+            Self::Idle => 19,
+            Self::BelowNormal => 10,
+            Self::Normal => 0,
+            Self::AboveNormal => -5,
+            Self::High => -10,
+        }
+    }
+
+    #[cfg(windows)]
+    pub(crate) fn creation_flag(self) -> winapi::shared::minwindef::DWORD {
+        use winapi::um::winbase::*;
+        match self {
+            Self::Idle => IDLE_PRIORITY_CLASS,
+            Self::BelowNormal => BELOW_NORMAL_PRIORITY_CLASS,
+            Self::Normal => NORMAL_PRIORITY_CLASS,
+            Self::AboveNormal => ABOVE_NORMAL_PRIORITY_CLASS,
+            Self::High => HIGH_PRIORITY_CLASS,
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "serde_support"))]
+fn default_controlling_tty() -> bool {
+    true
+}
+
 /// `CommandBuilder` is used to prepare a command to be spawned into a pty.
 /// The interface is intentionally similar to that of `std::process::Command`.
 #[derive(Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
 pub struct CommandBuilder {
     args: Vec<OsString>,
+    arg0: Option<OsString>,
     envs: BTreeMap<OsString, EnvEntry>,
     cwd: Option<OsString>,
+    pub(crate) kill_on_drop: bool,
+    pub(crate) new_process_group: bool,
+    pub(crate) priority: Option<PriorityClass>,
+    pub(crate) stdin: StdioMode,
+    pub(crate) stdout: StdioMode,
+    pub(crate) stderr: StdioMode,
+    pub(crate) initial_input: Vec<u8>,
     #[cfg(unix)]
     pub(crate) umask: Option<libc::mode_t>,
+    #[cfg(unix)]
+    #[cfg_attr(
+        feature = "serde_support",
+        serde(skip, default = "default_controlling_tty")
+    )]
+    pub(crate) controlling_tty: bool,
+    /// Descriptors are only meaningful in this process, so they are
+    /// not serialized
+    #[cfg(unix)]
+    #[cfg_attr(feature = "serde_support", serde(skip))]
+    pub(crate) inherit_fds: Vec<std::os::unix::io::RawFd>,
+    /// Platform specific settings aren't serialized, so that the
+    /// encoding of a builder sent over the mux protocol is the same on
+    /// every platform
+    #[cfg(windows)]
+    #[cfg_attr(feature = "serde_support", serde(skip))]
+    pub(crate) kill_on_drop_tree: bool,
+    #[cfg(windows)]
+    #[cfg_attr(feature = "serde_support", serde(skip))]
+    pub(crate) suspended: bool,
+    #[cfg(windows)]
+    #[cfg_attr(feature = "serde_support", serde(skip))]
+    pub(crate) retain_main_thread: bool,
+    /// The value of the parent process handle; this is kept as an
+    /// integer rather than a RawHandle so that the builder is Send
+    #[cfg(windows)]
+    #[cfg_attr(feature = "serde_support", serde(skip))]
+    pub(crate) parent_process: Option<usize>,
+    /// The value of the primary token handle, kept as an integer for
+    /// the same reason as `parent_process`
+    #[cfg(windows)]
+    #[cfg_attr(feature = "serde_support", serde(skip))]
+    pub(crate) token: Option<usize>,
+    /// Handle values to pass to the child, kept as integers for the
+    /// same reason as `parent_process`
+    #[cfg(windows)]
+    #[cfg_attr(feature = "serde_support", serde(skip))]
+    pub(crate) inherit_handles: Vec<usize>,
+    #[cfg(windows)]
+    #[cfg_attr(feature = "serde_support", serde(skip))]
+    pub(crate) code_page: Option<u32>,
 }
 
 impl CommandBuilder {
     /// Create a new builder instance with argv[0] set to the specified
     /// program.
     pub fn new<S: AsRef<OsStr>>(program: S) -> Self {
-        Self {
-            args: vec![program.as_ref().to_owned()],
-            envs: get_base_env(),
-            cwd: None,
-            #[cfg(unix)]
-            umask: None,
-        }
+        Self::from_argv(vec![program.as_ref().to_owned()])
     }
 
     /// Create a new builder instance from a pre-built argument vector
     pub fn from_argv(args: Vec<OsString>) -> Self {
         Self {
             args,
+            arg0: None,
             envs: get_base_env(),
             cwd: None,
+            kill_on_drop: false,
+            new_process_group: false,
+            priority: None,
+            stdin: StdioMode::Pty,
+            stdout: StdioMode::Pty,
+            stderr: StdioMode::Pty,
+            initial_input: vec![],
             #[cfg(unix)]
             umask: None,
+            #[cfg(unix)]
+            controlling_tty: true,
+            #[cfg(unix)]
+            inherit_fds: vec![],
+            #[cfg(windows)]
+            kill_on_drop_tree: false,
+            #[cfg(windows)]
+            suspended: false,
+            #[cfg(windows)]
+            retain_main_thread: false,
+            #[cfg(windows)]
+            parent_process: None,
+            #[cfg(windows)]
+            token: None,
+            #[cfg(windows)]
+            inherit_handles: vec![],
+            #[cfg(windows)]
+            code_page: None,
         }
     }
 
     /// Create a new builder instance that will run some idea of a default
     /// program.  Such a builder will panic if `arg` is called on it.
     pub fn new_default_prog() -> Self {
-        Self {
-            args: vec![],
-            envs: get_base_env(),
-            cwd: None,
-            #[cfg(unix)]
-            umask: None,
-        }
+        Self::from_argv(vec![])
     }
 
     /// Returns true if this builder was created via `new_default_prog`
@@ -219,10 +347,31 @@ impl CommandBuilder {
         }
     }
 
+    /// Set the name that the child sees as its `argv[0]`, independently
+    /// of the program that is run.  This is most commonly used to start
+    /// a login shell, which is conventionally signalled by prefixing the
+    /// name with `-`, as in `-bash`.
+    /// On Windows this only replaces the first token of the command
+    /// line; the path reported by `GetModuleFileName` is unaffected and
+    /// many programs ignore it.
+    pub fn arg0<S: AsRef<OsStr>>(&mut self, name: S) {
+        self.arg0 = Some(name.as_ref().to_owned());
+    }
+
+    /// Returns the program that will be run; this is the first element
+    /// of the argument vector.
+    /// Returns None if this builder was created via `new_default_prog`.
+    pub fn get_program(&self) -> Option<&OsStr> {
+        self.args.first().map(|arg| arg.as_os_str())
+    }
+
+    /// Returns the argument vector, including the program as its
+    /// first element
     pub fn get_argv(&self) -> &Vec<OsString> {
         &self.args
     }
 
+    /// Returns a mutable reference to the argument vector
     pub fn get_argv_mut(&mut self) -> &mut Vec<OsString> {
         &mut self.args
     }
@@ -245,6 +394,20 @@ impl CommandBuilder {
         );
     }
 
+    /// Override the values of a sequence of environmental variables
+    pub fn envs<I, K, V>(&mut self, vars: I)
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        for (key, value) in vars {
+            self.env(key, value);
+        }
+    }
+
+    /// Remove an environment variable, whether it was inherited from
+    /// the base environment or set via `env`
     pub fn env_remove<K>(&mut self, key: K)
     where
         K: AsRef<OsStr>,
@@ -253,6 +416,9 @@ impl CommandBuilder {
         self.envs.remove(&EnvEntry::map_key(key));
     }
 
+    /// Remove all environment variables, including those inherited from
+    /// the base environment, so that the child starts with only the
+    /// variables that are subsequently set via `env`
     pub fn env_clear(&mut self) {
         self.envs.clear();
     }
@@ -271,6 +437,11 @@ impl CommandBuilder {
         )
     }
 
+    /// Set the working directory for the child process.
+    /// This is the equivalent of `std::process::Command::current_dir`.
+    /// If the directory doesn't exist at the time that the command is
+    /// spawned, spawning fails with `PtyError::InvalidCwd` naming the
+    /// directory.
     pub fn cwd<D>(&mut self, dir: D)
     where
         D: AsRef<OsStr>,
@@ -278,14 +449,100 @@ impl CommandBuilder {
         self.cwd = Some(dir.as_ref().to_owned());
     }
 
+    /// Remove a previously configured working directory, so that
+    /// the child will be started in the home directory.
     pub fn clear_cwd(&mut self) {
         self.cwd.take();
     }
 
+    /// Returns the working directory configured via `cwd`, if any
     pub fn get_cwd(&self) -> Option<&OsString> {
         self.cwd.as_ref()
     }
 
+    /// Controls what happens to the child process when the `Child`
+    /// returned from `spawn_command` is dropped while the process is
+    /// still running.
+    /// When set to true, the process is killed.
+    /// The default is false, in which case dropping the `Child` simply
+    /// releases our handle on the process, leaving it running.
+    pub fn kill_on_drop(&mut self, kill_on_drop: bool) {
+        self.kill_on_drop = kill_on_drop;
+    }
+
+    /// When set to true, the child is started in a new process group
+    /// of its own, so that console control events that are generated
+    /// for the group of the parent are not delivered to it, and so that
+    /// it can be targeted individually by `GenerateConsoleCtrlEvent`.
+    /// On Windows this passes `CREATE_NEW_PROCESS_GROUP`, which also
+    /// causes the child to ignore `CTRL_C_EVENT` until it re-enables it.
+    /// On unix the child is always made the leader of a new session,
+    /// and therefore of a new process group, so this has no additional
+    /// effect.
+    /// The default is false.
+    pub fn new_process_group(&mut self, enable: bool) {
+        self.new_process_group = enable;
+    }
+
+    /// Sets the scheduling priority of the child, for example to run
+    /// a background build at below normal priority so that interactive
+    /// programs remain responsive.
+    /// The default is None, which leaves the priority up to the OS;
+    /// typically the child inherits that of its parent.
+    pub fn priority(&mut self, class: Option<PriorityClass>) {
+        self.priority = class;
+    }
+
+    /// Configures what the child's stdin is connected to.
+    /// The default is `StdioMode::Pty`.
+    pub fn stdin(&mut self, mode: StdioMode) {
+        self.stdin = mode;
+    }
+
+    /// Configures what the child's stdout is connected to.
+    /// The default is `StdioMode::Pty`.
+    pub fn stdout(&mut self, mode: StdioMode) {
+        self.stdout = mode;
+    }
+
+    /// Configures what the child's stderr is connected to.
+    /// The default is `StdioMode::Pty`, in which case stdout and stderr
+    /// are written to the same pty and are read back from the master
+    /// as a single stream, in the order that the child wrote them.
+    /// Setting it to `StdioMode::Inherit` is useful for keeping
+    /// diagnostic output out of the terminal, while `StdioMode::Pipe`
+    /// allows reading it separately via `Child::take_stderr`; note that
+    /// the relative ordering of stdout and stderr is lost in that case.
+    /// The pty remains the controlling terminal (or the attached
+    /// console, on Windows) of the child even if none of its standard
+    /// streams are connected to it, so a program that explicitly opens
+    /// its terminal (eg: `/dev/tty` or `CONOUT$`) will still reach it.
+    pub fn stderr(&mut self, mode: StdioMode) {
+        self.stderr = mode;
+    }
+
+    /// Queue `input` to be written to the pty as soon as the command
+    /// has been spawned.  This is useful for feeding a sequence of
+    /// commands to a shell.
+    /// The pty buffers the input until the child reads it, so the child
+    /// doesn't need to be ready for it; with conpty the input is only
+    /// written once the process has been created, by which time the
+    /// pseudoconsole is accepting input.
+    /// The input is subject to the line discipline in the same way as
+    /// any other input, so it is typically echoed back, and a shell
+    /// needs a line ending (`\n` on unix, `\r` with conpty) before it
+    /// acts on a command.
+    /// With the unix, conpty and winpty ptys the input is written on a
+    /// background thread, so spawning doesn't wait for the child to read
+    /// input that doesn't fit in the pty's input buffer, which on unix is
+    /// typically 4KiB.  The input isn't ordered relative to anything
+    /// written via the `MasterPty` after spawning, and a failure to write
+    /// it is only logged.
+    /// This is honored by the unix, conpty, winpty and ssh ptys.
+    pub fn initial_input<B: AsRef<[u8]>>(&mut self, input: B) {
+        self.initial_input = input.as_ref().to_vec();
+    }
+
     /// Iterate over the configured environment. Only includes environment
     /// variables set by the caller via `env`, not variables set in the base
     /// environment.
@@ -307,6 +564,22 @@ impl CommandBuilder {
         )
     }
 
+    /// Summarizes the environment for logging: returns whether any of
+    /// the environment of this process is passed on to the child, and
+    /// the names of the variables that were explicitly set via `env`.
+    /// Only the names are included because the values frequently hold
+    /// secrets such as access tokens.
+    pub(crate) fn env_summary(&self) -> (bool, Vec<&OsStr>) {
+        let inherited = self.envs.values().any(|entry| entry.is_from_base_env);
+        let set = self
+            .envs
+            .values()
+            .filter(|entry| !entry.is_from_base_env)
+            .map(|entry| entry.preferred_key.as_os_str())
+            .collect();
+        (inherited, set)
+    }
+
     /// Return the configured command and arguments as a single string,
     /// quoted per the unix shell conventions.
     pub fn as_unix_command_line(&self) -> anyhow::Result<String> {
@@ -321,18 +594,79 @@ impl CommandBuilder {
     }
 }
 
+/// Converts a `std::process::Command` into a `CommandBuilder`.
+/// The program, arguments, environment overrides and removals, and the
+/// working directory are carried across.
+/// Stdio redirection, `env_clear` and platform specific settings such as
+/// `pre_exec` or `creation_flags` cannot be inspected on a
+/// `std::process::Command` and are therefore not preserved.
+impl From<std::process::Command> for CommandBuilder {
+    fn from(cmd: std::process::Command) -> Self {
+        let mut builder = CommandBuilder::new(cmd.get_program());
+        builder.args(cmd.get_args());
+        for (key, value) in cmd.get_envs() {
+            match value {
+                Some(value) => builder.env(key, value),
+                None => builder.env_remove(key),
+            }
+        }
+        if let Some(dir) = cmd.get_current_dir() {
+            builder.cwd(dir);
+        }
+        builder
+    }
+}
+
 #[cfg(unix)]
 impl CommandBuilder {
     pub fn umask(&mut self, mask: Option<libc::mode_t>) {
         self.umask = mask;
     }
 
+    /// Controls whether the pty is made the controlling terminal of the
+    /// child.  The child is always made the leader of a new session;
+    /// when this is enabled the slave is then attached to that session
+    /// via `TIOCSCTTY`, which is what allows job control to work: the
+    /// terminal generates `SIGINT` for Ctrl-C and `SIGWINCH` on resize
+    /// for the foreground process group, and `process_group_leader`
+    /// reports that group.
+    /// Disable it for children that must not be affected by the
+    /// terminal, such as daemons; they then have no controlling
+    /// terminal at all.
+    /// The default is true.
+    pub fn controlling_tty(&mut self, enable: bool) {
+        self.controlling_tty = enable;
+    }
+
+    /// Keep `fd` open in the child, under the same number, so that it
+    /// can be handed a pre-opened descriptor such as a socket.
+    /// Ordinarily all descriptors other than the standard streams are
+    /// closed in the child.  The caller must keep `fd` open until the
+    /// command has been spawned, and must tell the child which number
+    /// to use, for example via an argument or environment variable.
+    /// Descriptors 0, 1 and 2 are replaced by the standard streams and
+    /// cannot be passed this way.
+    pub fn inherit_fd(&mut self, fd: std::os::unix::io::RawFd) {
+        if !self.inherit_fds.contains(&fd) {
+            self.inherit_fds.push(fd);
+        }
+    }
+
     fn resolve_path(&self) -> Option<&OsStr> {
         self.get_env("PATH")
     }
 
     fn search_path(&self, exe: &OsStr, cwd: &OsStr) -> anyhow::Result<OsString> {
         use std::path::Path;
+        // An empty name would otherwise resolve to the cwd itself, or to
+        // a directory in PATH
+        if exe.to_str().map_or(false, |exe| exe.trim().is_empty()) {
+            return Err(crate::PtyError::InvalidProgram {
+                program: exe.to_owned(),
+                reason: "the program name is empty".to_string(),
+            }
+            .into());
+        }
         let exe_path: &Path = exe.as_ref();
         if exe_path.is_relative() {
             let cwd: &Path = cwd.as_ref();
@@ -371,12 +705,13 @@ impl CommandBuilder {
         use std::os::unix::process::CommandExt;
 
         let home = self.get_home_dir()?;
-        let dir: &OsStr = self
-            .cwd
-            .as_ref()
-            .map(|dir| dir.as_os_str())
-            .filter(|dir| std::path::Path::new(dir).is_dir())
-            .unwrap_or(home.as_ref());
+        let dir: &OsStr = match &self.cwd {
+            Some(dir) if !std::path::Path::new(dir).is_dir() => {
+                return Err(crate::PtyError::InvalidCwd { cwd: dir.clone() }.into());
+            }
+            Some(dir) => dir.as_os_str(),
+            None => home.as_ref(),
+        };
 
         let mut cmd = if self.is_default_prog() {
             let shell = self.get_shell()?;
@@ -384,14 +719,20 @@ impl CommandBuilder {
             let mut cmd = std::process::Command::new(&shell);
 
             // Run the shell as a login shell by prefixing the shell's
-            // basename with `-` and setting that as argv0
-            let basename = shell.rsplit('/').next().unwrap_or(&shell);
-            cmd.arg0(&format!("-{}", basename));
+            // basename with `-` and setting that as argv0, unless the
+            // caller has chosen their own argv0
+            match &self.arg0 {
+                Some(arg0) => cmd.arg0(arg0),
+                None => {
+                    let basename = shell.rsplit('/').next().unwrap_or(&shell);
+                    cmd.arg0(&format!("-{}", basename))
+                }
+            };
             cmd
         } else {
             let resolved = self.search_path(&self.args[0], dir)?;
             let mut cmd = std::process::Command::new(&resolved);
-            cmd.arg0(&self.args[0]);
+            cmd.arg0(self.arg0.as_ref().unwrap_or(&self.args[0]));
             cmd.args(&self.args[1..]);
             cmd
         };
@@ -453,44 +794,218 @@ impl CommandBuilder {
 
 #[cfg(windows)]
 impl CommandBuilder {
-    fn search_path(&self, exe: &OsStr) -> OsString {
+    /// Create a builder that launches a shell in a WSL distribution by
+    /// running `wsl.exe`, optionally with `--distribution <distro>`;
+    /// the default distribution is used if `distro` is None.
+    ///
+    /// The shell starts in `linux_cwd`, such as `~` or `/srv`, if that
+    /// is specified, via `--cd`.  Otherwise `wsl.exe` translates the
+    /// Windows working directory of the builder, so a cwd of
+    /// `C:\Users\me` starts the shell in `/mnt/c/Users/me`; a builder
+    /// cwd is only honored if it is a Windows path.
+    ///
+    /// Arguments added to the returned builder are passed to `wsl.exe`
+    /// after these options, which runs them as a command line via the
+    /// default shell of the user.  Note that:
+    ///
+    /// * Without arguments the default shell is started as an
+    ///   interactive login shell, so it reads the profile of the user.
+    ///   A command is run by a non-interactive, non-login shell, so
+    ///   anything that the profile or `.bashrc` adds to `PATH` is
+    ///   missing; use something like `bash -lic <command>` as the
+    ///   arguments if that matters.
+    /// * Environment variables set on the builder only reach the Linux
+    ///   side if they are listed in `WSLENV`.
+    /// * Conpty presents the output of the Linux processes as UTF-8,
+    ///   so no code page setup is needed inside the distribution.
+    ///   `WSL_UTF8=1` is set so that diagnostics that `wsl.exe` prints
+    ///   itself, such as an error about an unknown distribution, are
+    ///   also UTF-8 rather than UTF-16.
+    pub fn new_wsl(distro: Option<&str>, linux_cwd: Option<&str>) -> Self {
+        let mut cmd = Self::new("wsl.exe");
+        if let Some(distro) = distro {
+            cmd.args(&["--distribution", distro]);
+        }
+        if let Some(dir) = linux_cwd {
+            cmd.args(&["--cd", dir]);
+        }
+        cmd.env("WSL_UTF8", "1");
+        cmd
+    }
+
+    /// When enabled, the spawned process is assigned to a Job Object
+    /// that terminates all of its member processes when the job is closed.
+    /// The job handle is owned by the returned `Child` and by any killers
+    /// cloned from it, so `kill`, or dropping the last of those objects,
+    /// terminates the entire process tree rather than just the immediate
+    /// child.
+    pub fn kill_on_drop_tree(&mut self, enable: bool) {
+        self.kill_on_drop_tree = enable;
+    }
+
+    /// When enabled, the process is created with its main thread
+    /// suspended, which allows the caller to prepare for it, for
+    /// example by installing hooks, before it runs.
+    /// Use `Child::resume` to allow it to run.
+    pub fn suspended(&mut self, suspended: bool) {
+        self.suspended = suspended;
+    }
+
+    /// When enabled, the handle to the main thread of the process is
+    /// retained by the returned `Child` and is available via
+    /// `Child::main_thread_handle`, for example to adjust its priority.
+    /// The handle is closed when the `Child` is dropped.
+    /// The default is false, in which case the handle is closed as soon
+    /// as the process has been spawned.
+    pub fn retain_main_thread(&mut self, retain: bool) {
+        self.retain_main_thread = retain;
+    }
+
+    /// Create the process as though it had been spawned by the process
+    /// identified by `handle`, which must have been opened with
+    /// `PROCESS_CREATE_PROCESS` access and must remain open until the
+    /// command has been spawned.  The child inherits attributes such as
+    /// the job and AppContainer of that process.
+    /// Because any inherited handles would have to come from that
+    /// process, this cannot be combined with stdio redirection.
+    pub fn parent_process(&mut self, handle: std::os::windows::io::RawHandle) {
+        self.parent_process = Some(handle as usize);
+    }
+
+    /// Run the process in the security context of the primary `token`,
+    /// such as a restricted or AppContainer token, by spawning it with
+    /// `CreateProcessAsUserW` rather than `CreateProcessW`.  The
+    /// pseudoconsole is attached in the same way in either case.
+    /// The token must have been opened with `TOKEN_QUERY`,
+    /// `TOKEN_DUPLICATE` and `TOKEN_ASSIGN_PRIMARY` access and must
+    /// remain open until the command has been spawned.
+    /// Unless the token is a restricted version of the caller's own
+    /// token, the caller needs the `SeAssignPrimaryTokenPrivilege`
+    /// privilege, and it may also need `SeIncreaseQuotaPrivilege`;
+    /// without them spawning fails with `ERROR_PRIVILEGE_NOT_HELD`,
+    /// which is reported as a `PtyError::Spawn` error.
+    /// winpty can't spawn the process with a different token, so it
+    /// refuses to spawn a command for which this is set.
+    pub fn with_token(&mut self, token: std::os::windows::io::RawHandle) {
+        self.token = Some(token as usize);
+    }
+
+    /// Pass `handle` to the child, in addition to its standard streams,
+    /// so that it can be handed a pre-opened handle such as a socket.
+    /// The handle has the same value in the child, which the caller
+    /// must communicate to it, for example via an argument or
+    /// environment variable, and must remain open until the command
+    /// has been spawned.
+    /// The handle is marked as inheritable for the duration of the
+    /// spawn and its original flag is restored afterwards; another
+    /// thread that spawns a process with `bInheritHandles` set and
+    /// without a `PROC_THREAD_ATTRIBUTE_HANDLE_LIST` in the meantime
+    /// may also inherit it.
+    /// The child is spawned with a `PROC_THREAD_ATTRIBUTE_HANDLE_LIST`
+    /// alongside the `PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE` attribute,
+    /// so it inherits exactly these handles and those of any
+    /// redirected standard streams.  The pseudoconsole is attached
+    /// independently of handle inheritance, so this doesn't affect the
+    /// console handles of the child.
+    /// Like stdio redirection, this cannot be combined with
+    /// `parent_process`.
+    pub fn inherit_handle(&mut self, handle: std::os::windows::io::RawHandle) {
+        let handle = handle as usize;
+        // The handle list must not contain duplicates
+        if !self.inherit_handles.contains(&handle) {
+            self.inherit_handles.push(handle);
+        }
+    }
+
+    /// Set the input and output code page of the console to `code_page`,
+    /// as `SetConsoleCP` and `SetConsoleOutputCP` would, before the
+    /// command is spawned.  This is the code page that the console uses
+    /// to translate the bytes that the child reads and writes via
+    /// `ReadFile`, `WriteFile` and the `-A` console APIs, so it matters
+    /// for programs that aren't Unicode aware, such as those that print
+    /// text encoded in a legacy code page.  It doesn't affect the output
+    /// read from the master: conpty always encodes that as UTF-8,
+    /// translating from the code page as required.
+    /// The default is the OEM code page of the system.
+    /// Code pages belong to the console rather than to a process, so the
+    /// setting remains in effect for commands that are subsequently
+    /// spawned in the same pty, and can be changed again by the child.
+    /// If the code page is not valid, spawning fails.
+    /// This is only supported by conpty; winpty fails to spawn the
+    /// command if it is set.
+    pub fn code_page(&mut self, code_page: u32) {
+        self.code_page = Some(code_page);
+    }
+
+    fn search_path(&self, exe: &OsStr) -> anyhow::Result<OsString> {
+        use std::path::Path;
+        let exe_path = Path::new(exe);
+
+        // Absolute paths, and relative paths that name a directory,
+        // are used as-is rather than being searched for
+        let has_dir = exe_path
+            .parent()
+            .map(|p| !p.as_os_str().is_empty())
+            .unwrap_or(false);
+        if exe_path.is_absolute() || has_dir {
+            return Ok(exe.to_owned());
+        }
+
+        let extensions = self.get_env("PATHEXT").unwrap_or(OsStr::new(".EXE"));
         if let Some(path) = self.get_env("PATH") {
-            let extensions = self.get_env("PATHEXT").unwrap_or(OsStr::new(".EXE"));
             for path in std::env::split_paths(&path) {
                 // Check for exactly the user's string in this path dir
                 let candidate = path.join(&exe);
                 if candidate.exists() {
-                    return candidate.into_os_string();
+                    return Ok(candidate.into_os_string());
                 }
 
                 // otherwise try tacking on some extensions.
-                // Note that this really replaces the extension in the
-                // user specified path, so this is potentially wrong.
                 for ext in std::env::split_paths(&extensions) {
-                    // PATHEXT includes the leading `.`, but `with_extension`
-                    // doesn't want that
-                    let ext = ext.to_str().expect("PATHEXT entries must be utf8");
-                    let path = path.join(&exe).with_extension(&ext[1..]);
-                    if path.exists() {
-                        return path.into_os_string();
+                    let mut candidate = candidate.clone().into_os_string();
+                    candidate.push(&ext);
+                    if Path::new(&candidate).exists() {
+                        return Ok(candidate);
                     }
                 }
             }
         }
 
-        exe.to_owned()
+        // CreateProcessW resolves a relative lpApplicationName against
+        // the current directory of this process, so allow for that
+        if exe_path.exists() {
+            return Ok(exe.to_owned());
+        }
+
+        Err(crate::PtyError::InvalidProgram {
+            program: exe.to_owned(),
+            reason: format!(
+                "it doesn't exist on the filesystem and was not found in \
+                 PATH={:?} using PATHEXT={:?}",
+                self.get_env("PATH").unwrap_or_default(),
+                extensions
+            ),
+        }
+        .into())
     }
 
-    pub(crate) fn current_directory(&self) -> Option<Vec<u16>> {
+    pub(crate) fn current_directory(&self) -> anyhow::Result<Option<Vec<u16>>> {
         use std::path::Path;
 
-        let home: Option<&OsStr> = self
-            .get_env("USERPROFILE")
-            .filter(|path| Path::new(path).is_dir());
-        let cwd: Option<&OsStr> = self.cwd.as_deref().filter(|path| Path::new(path).is_dir());
-        let dir: Option<&OsStr> = cwd.or(home);
+        let dir: Option<&OsStr> = match self.cwd.as_deref() {
+            Some(cwd) if !Path::new(cwd).is_dir() => {
+                return Err(crate::PtyError::InvalidCwd {
+                    cwd: cwd.to_owned(),
+                }
+                .into());
+            }
+            Some(cwd) => Some(cwd),
+            None => self
+                .get_env("USERPROFILE")
+                .filter(|path| Path::new(path).is_dir()),
+        };
 
-        dir.map(|dir| {
+        Ok(dir.map(|dir| {
             let mut wide = vec![];
 
             if Path::new(dir).is_relative() {
@@ -505,7 +1020,7 @@ impl CommandBuilder {
 
             wide.push(0);
             wide
-        })
+        }))
     }
 
     /// Constructs an environment block for this spawn attempt.
@@ -527,6 +1042,10 @@ impl CommandBuilder {
             block.extend(value.encode_wide());
             block.push(0);
         }
+        if block.is_empty() {
+            // An empty block still needs to be doubly terminated
+            block.push(0);
+        }
         // and a final terminator for CreateProcessW
         block.push(0);
 
@@ -551,10 +1070,27 @@ impl CommandBuilder {
                 .unwrap_or(OsStr::new("cmd.exe"))
                 .into()
         } else {
-            self.search_path(&self.args[0])
+            // Leading and trailing whitespace is almost certainly a
+            // mistake, such as a program name read from a config file,
+            // and would otherwise fail to resolve
+            let program: OsString = match self.args[0].to_str() {
+                Some(program) => program.trim().into(),
+                None => self.args[0].clone(),
+            };
+            if program.is_empty() {
+                return Err(crate::PtyError::InvalidProgram {
+                    program,
+                    reason: "the program name is empty".to_string(),
+                }
+                .into());
+            }
+            self.search_path(&program)?
         };
 
-        Self::append_quoted(&exe, &mut cmdline);
+        Self::append_quoted(
+            self.arg0.as_deref().unwrap_or(exe.as_os_str()),
+            &mut cmdline,
+        );
 
         // Ensure that we nul terminate the module name, otherwise we'll
         // ask CreateProcessW to start something random!
@@ -572,6 +1108,15 @@ impl CommandBuilder {
         }
         // Ensure that the command line is nul terminated too!
         cmdline.push(0);
+        // CreateProcessW limits the command line, including the nul
+        // terminator, to 32767 characters and fails with an unhelpful
+        // error if it is longer
+        if cmdline.len() > 32767 {
+            return Err(crate::PtyError::CommandLineTooLong {
+                len: cmdline.len() - 1,
+            }
+            .into());
+        }
         Ok((exe, cmdline))
     }
 
@@ -627,6 +1172,32 @@ impl CommandBuilder {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_argv() {
+        let mut cmd = CommandBuilder::new("prog");
+        cmd.arg("one");
+        cmd.args(&["two", "three"]);
+        assert_eq!(cmd.get_program(), Some(OsStr::new("prog")));
+        assert_eq!(cmd.get_argv(), &vec!["prog", "one", "two", "three"]);
+
+        assert_eq!(CommandBuilder::new_default_prog().get_program(), None);
+    }
+
+    #[test]
+    fn test_from_std_command() {
+        let mut std_cmd = std::process::Command::new("prog");
+        std_cmd.arg("one");
+        std_cmd.env("foo key", "foo value");
+        std_cmd.env_remove("CARGO_PKG_AUTHORS");
+        std_cmd.current_dir("/some/dir");
+
+        let cmd = CommandBuilder::from(std_cmd);
+        assert_eq!(cmd.get_argv(), &vec!["prog", "one"]);
+        assert_eq!(cmd.get_env("foo key"), Some(OsStr::new("foo value")));
+        assert!(cmd.get_env("CARGO_PKG_AUTHORS").is_none());
+        assert_eq!(cmd.get_cwd(), Some(&OsString::from("/some/dir")));
+    }
+
     #[test]
     fn test_env() {
         let mut cmd = CommandBuilder::new("dummy");
@@ -679,4 +1250,208 @@ mod tests {
         cmd.env_remove("cARGO_pKG_aUTHORS");
         assert!(cmd.get_env("CARGO_PKG_AUTHORS").is_none());
     }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_append_quoted_round_trip() {
+        use std::os::windows::ffi::OsStringExt;
+        use winapi::um::shellapi::CommandLineToArgvW;
+        use winapi::um::winbase::LocalFree;
+
+        let args = [
+            "plain",
+            "a\\b",
+            "a\"b",
+            "a\\",
+            "a b\\",
+            "",
+            "a\\\\\"b",
+            "tab\there",
+        ];
+
+        let mut cmdline = vec![];
+        CommandBuilder::append_quoted(OsStr::new("prog"), &mut cmdline);
+        for arg in &args {
+            cmdline.push(' ' as u16);
+            CommandBuilder::append_quoted(OsStr::new(arg), &mut cmdline);
+        }
+        cmdline.push(0);
+
+        let mut argc = 0;
+        let argv = unsafe { CommandLineToArgvW(cmdline.as_ptr(), &mut argc) };
+        assert!(!argv.is_null());
+        let parsed: Vec<OsString> = (0..argc as usize)
+            .map(|i| unsafe {
+                let arg = *argv.add(i);
+                let len = (0..).take_while(|&j| *arg.add(j) != 0).count();
+                OsString::from_wide(std::slice::from_raw_parts(arg, len))
+            })
+            .collect();
+        unsafe { LocalFree(argv as _) };
+
+        assert_eq!(&parsed[1..], &args[..]);
+    }
+
+    #[test]
+    fn test_env_summary() {
+        let mut cmd = CommandBuilder::new("dummy");
+        cmd.env("SECRET_TOKEN", "hunter2");
+        let (inherited, set) = cmd.env_summary();
+        assert!(inherited);
+        assert_eq!(set, vec![OsStr::new("SECRET_TOKEN")]);
+
+        cmd.env_clear();
+        assert_eq!(cmd.env_summary(), (false, vec![]));
+    }
+
+    /// The error for a failed spawn is logged and shown to the user, so
+    /// it mustn't reveal environment values either
+    #[cfg(unix)]
+    #[test]
+    fn test_env_omitted_from_spawn_error() {
+        let pair = crate::native_pty_system()
+            .openpty(crate::PtySize::default())
+            .unwrap();
+        let mut cmd = CommandBuilder::new("/");
+        cmd.env("SECRET_TOKEN", "hunter2");
+        let err = pair.slave.spawn_command(cmd).unwrap_err();
+        for message in &[
+            format!("{}", err),
+            format!("{:#}", err),
+            format!("{:?}", err),
+        ] {
+            assert!(
+                message.contains("failed to spawn"),
+                "message was {:?}",
+                message
+            );
+            assert!(!message.contains("hunter2"), "message was {:?}", message);
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_environment_block() {
+        let mut cmd = CommandBuilder::new("dummy");
+        cmd.env_clear();
+        cmd.env("Foo", "a=b=c");
+        cmd.env("FOO", "replaced");
+        cmd.env("Bar", "");
+
+        let block = String::from_utf16(&cmd.environment_block()).unwrap();
+        assert_eq!(block, "Bar=\0FOO=replaced\0\0");
+
+        cmd.env_clear();
+        assert_eq!(cmd.environment_block(), vec![0, 0]);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_cmdline_too_long() {
+        let mut cmd = CommandBuilder::new("C:\\dummy.exe");
+        cmd.arg("a".repeat(32000));
+        assert!(cmd.cmdline().is_ok());
+
+        cmd.arg("b".repeat(1000));
+        let err = cmd.cmdline().unwrap_err();
+        match err.downcast_ref::<crate::PtyError>() {
+            Some(crate::PtyError::CommandLineTooLong { len }) => assert_eq!(*len, 33014),
+            _ => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_new_wsl() {
+        let cmd = CommandBuilder::new_wsl(None, None);
+        assert_eq!(cmd.get_argv(), &vec!["wsl.exe"]);
+        assert_eq!(cmd.get_env("WSL_UTF8"), Some(OsStr::new("1")));
+
+        let mut cmd = CommandBuilder::new_wsl(Some("Ubuntu 22.04"), Some("~"));
+        cmd.arg("ls");
+        assert_eq!(
+            cmd.get_argv(),
+            &vec![
+                "wsl.exe",
+                "--distribution",
+                "Ubuntu 22.04",
+                "--cd",
+                "~",
+                "ls"
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_invalid_cwd() {
+        let mut cmd = CommandBuilder::new("true");
+        cmd.cwd("/this/directory/does/not/exist");
+        let err = cmd.as_command().unwrap_err();
+        match err.downcast_ref::<crate::PtyError>() {
+            Some(crate::PtyError::InvalidCwd { cwd }) => {
+                assert_eq!(cwd, "/this/directory/does/not/exist")
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_as_command_invalid_program() {
+        for program in &["", "   "] {
+            let cmd = CommandBuilder::new(program);
+            let err = cmd.as_command().unwrap_err();
+            match err.downcast_ref::<crate::PtyError>() {
+                Some(crate::PtyError::InvalidProgram { program: p, .. }) => {
+                    assert_eq!(p, program)
+                }
+                _ => panic!("unexpected error {:?}", err),
+            }
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_invalid_cwd() {
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.cwd("C:\\this\\directory\\does\\not\\exist");
+        let err = cmd.current_directory().unwrap_err();
+        match err.downcast_ref::<crate::PtyError>() {
+            Some(crate::PtyError::InvalidCwd { cwd }) => {
+                assert_eq!(cwd, "C:\\this\\directory\\does\\not\\exist")
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_cmdline_invalid_program() {
+        for program in &["", "   "] {
+            let cmd = CommandBuilder::new(program);
+            let err = cmd.cmdline().unwrap_err();
+            match err.downcast_ref::<crate::PtyError>() {
+                Some(crate::PtyError::InvalidProgram { program, .. }) => {
+                    assert!(program.is_empty())
+                }
+                _ => panic!("unexpected error {:?}", err),
+            }
+        }
+
+        let cmd = CommandBuilder::new("this-program-does-not-exist.exe");
+        let err = cmd.cmdline().unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<crate::PtyError>(),
+                Some(crate::PtyError::InvalidProgram { .. })
+            ),
+            "unexpected error {:?}",
+            err
+        );
+
+        let cmd = CommandBuilder::new("  C:\\dummy.exe  ");
+        let (exe, _) = cmd.cmdline().unwrap();
+        assert_eq!(String::from_utf16(&exe).unwrap(), "C:\\dummy.exe\0");
+    }
 }
@@ -0,0 +1,281 @@
+//! Coalesces bursts of resize requests, such as those produced while
+//! the user drags the edge of a window, into a single resize.
+use crate::{MasterPty, PtyError, PtyHandleSet, PtySize, ReadableToken};
+use anyhow::Error;
+use std::io::{Read, Result as IoResult, Write};
+#[cfg(windows)]
+use std::os::windows::io::RawHandle;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct State {
+    /// The most recently requested size and when it was requested
+    pending: Option<(PtySize, Instant)>,
+    shutdown: bool,
+}
+
+/// Wraps a `MasterPty` so that `resize` calls made in quick succession
+/// are coalesced: the size is only applied once no further resize has
+/// been requested for the configured delay, and only the last of the
+/// requested sizes is applied.  Resizing conpty is relatively expensive
+/// and each resize causes it to reflow its buffer, so applying every
+/// intermediate size while a window is being dragged is both slow and
+/// likely to leave a child redrawing at a stale size.
+///
+/// Resizes are applied on a background thread, so `resize` returns
+/// immediately and errors from the underlying resize are logged rather
+/// than returned.  `get_size` reports the pending size, if any.
+/// A pending resize is applied when the wrapper is dropped.
+///
+/// Use the `MasterPty` directly for immediate resizes.
+///
+/// Reading directly from the wrapper via `read_output` is not
+/// supported, as a blocked read would prevent resizing; use
+/// `try_clone_reader` instead.
+pub struct DebouncedMasterPty {
+    master: Arc<Mutex<Box<dyn MasterPty + Send>>>,
+    writer: Box<dyn Write + Send>,
+    state: Arc<(Mutex<State>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DebouncedMasterPty {
+    /// Wraps `master`, applying a requested size once `delay` has passed
+    /// without another resize being requested.  A delay of around 50ms
+    /// is enough to coalesce the resizes produced by dragging a window.
+    pub fn new(master: Box<dyn MasterPty + Send>, delay: Duration) -> Result<Self, Error> {
+        let writer = master.try_clone_writer()?;
+        let master = Arc::new(Mutex::new(master));
+        let state = Arc::new((Mutex::new(State::default()), Condvar::new()));
+
+        let thread = {
+            let master = Arc::clone(&master);
+            let state = Arc::clone(&state);
+            std::thread::Builder::new()
+                .name("pty resize".to_string())
+                .spawn(move || apply_resizes(&master, &state, delay))?
+        };
+
+        Ok(Self {
+            master,
+            writer,
+            state,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// The body of the background thread
+fn apply_resizes(
+    master: &Mutex<Box<dyn MasterPty + Send>>,
+    state: &(Mutex<State>, Condvar),
+    delay: Duration,
+) {
+    let (lock, cvar) = state;
+    let mut guard = lock.lock().unwrap();
+    loop {
+        let (size, requested) = match guard.pending {
+            Some(pending) => pending,
+            None if guard.shutdown => return,
+            None => {
+                guard = cvar.wait(guard).unwrap();
+                continue;
+            }
+        };
+
+        let elapsed = requested.elapsed();
+        if elapsed < delay && !guard.shutdown {
+            guard = cvar.wait_timeout(guard, delay - elapsed).unwrap().0;
+            continue;
+        }
+
+        guard.pending.take();
+        drop(guard);
+        match master.lock().unwrap().resize(size) {
+            Ok(()) => {}
+            // The child went away before the resize was applied
+            Err(err) if matches!(err.downcast_ref(), Some(PtyError::PtyClosed(_))) => {
+                log::debug!("{:#}", err)
+            }
+            Err(err) => log::error!("{:#}", err),
+        }
+        guard = lock.lock().unwrap();
+    }
+}
+
+impl Drop for DebouncedMasterPty {
+    fn drop(&mut self) {
+        {
+            let (lock, cvar) = &*self.state;
+            lock.lock().unwrap().shutdown = true;
+            cvar.notify_one();
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+impl MasterPty for DebouncedMasterPty {
+    fn resize(&self, size: PtySize) -> Result<(), Error> {
+        let (lock, cvar) = &*self.state;
+        lock.lock().unwrap().pending = Some((size, Instant::now()));
+        cvar.notify_one();
+        Ok(())
+    }
+
+    fn get_size(&self) -> Result<PtySize, Error> {
+        let (lock, _) = &*self.state;
+        if let Some((size, _)) = lock.lock().unwrap().pending {
+            return Ok(size);
+        }
+        self.master.lock().unwrap().get_size()
+    }
+
+    fn try_clone_reader(&self) -> Result<Box<dyn Read + Send>, Error> {
+        self.master.lock().unwrap().try_clone_reader()
+    }
+
+    fn read_available(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.master.lock().unwrap().read_available(buf)
+    }
+
+    fn readable_token(&self) -> Result<ReadableToken, Error> {
+        self.master.lock().unwrap().readable_token()
+    }
+
+    fn try_clone_writer(&self) -> Result<Box<dyn Write + Send>, Error> {
+        self.master.lock().unwrap().try_clone_writer()
+    }
+
+    fn handle_set(&self) -> Result<PtyHandleSet, Error> {
+        self.master.lock().unwrap().handle_set()
+    }
+
+    fn send_eof(&self) -> Result<(), Error> {
+        self.master.lock().unwrap().send_eof()
+    }
+
+    #[cfg(unix)]
+    fn process_group_leader(&self) -> Option<libc::pid_t> {
+        self.master.lock().unwrap().process_group_leader()
+    }
+
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        self.master.lock().unwrap().as_raw_fd()
+    }
+
+    #[cfg(windows)]
+    fn as_raw_handles(&self) -> Option<(RawHandle, RawHandle)> {
+        self.master.lock().unwrap().as_raw_handles()
+    }
+}
+
+impl Write for DebouncedMasterPty {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.writer.write(buf)
+    }
+    fn flush(&mut self) -> IoResult<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the sizes that it was resized to
+    struct RecordingMaster {
+        sizes: Arc<Mutex<Vec<PtySize>>>,
+    }
+
+    impl MasterPty for RecordingMaster {
+        fn resize(&self, size: PtySize) -> Result<(), Error> {
+            self.sizes.lock().unwrap().push(size);
+            Ok(())
+        }
+        fn get_size(&self) -> Result<PtySize, Error> {
+            Ok(self
+                .sizes
+                .lock()
+                .unwrap()
+                .last()
+                .copied()
+                .unwrap_or_default())
+        }
+        fn try_clone_reader(&self) -> Result<Box<dyn Read + Send>, Error> {
+            Ok(Box::new(std::io::empty()))
+        }
+        fn try_clone_writer(&self) -> Result<Box<dyn Write + Send>, Error> {
+            Ok(Box::new(std::io::sink()))
+        }
+        #[cfg(unix)]
+        fn process_group_leader(&self) -> Option<libc::pid_t> {
+            None
+        }
+    }
+
+    impl Write for RecordingMaster {
+        fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    fn size(cols: u16) -> PtySize {
+        PtySize {
+            cols,
+            ..PtySize::default()
+        }
+    }
+
+    #[test]
+    fn coalesces_resizes() {
+        let sizes = Arc::new(Mutex::new(vec![]));
+        let master = RecordingMaster {
+            sizes: Arc::clone(&sizes),
+        };
+        let debounced =
+            DebouncedMasterPty::new(Box::new(master), Duration::from_millis(100)).unwrap();
+        for cols in 1..=200 {
+            debounced.resize(size(cols)).unwrap();
+        }
+        assert_eq!(debounced.get_size().unwrap(), size(200));
+
+        // Wait for the final size to be applied, rather than sleeping
+        // for a fixed time that a loaded machine may exceed
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while sizes.lock().unwrap().last() != Some(&size(200)) {
+            assert!(Instant::now() < deadline, "the resize was never applied");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // Intermediate sizes are only applied if requesting them stalled
+        // for longer than the delay, and they must still be in order
+        let applied = sizes.lock().unwrap().clone();
+        assert!(applied.len() < 200, "applied {:?}", applied);
+        assert!(
+            applied.windows(2).all(|w| w[0].cols < w[1].cols),
+            "applied {:?}",
+            applied
+        );
+        assert_eq!(debounced.get_size().unwrap(), size(200));
+    }
+
+    #[test]
+    fn applies_pending_resize_on_drop() {
+        let sizes = Arc::new(Mutex::new(vec![]));
+        let master = RecordingMaster {
+            sizes: Arc::clone(&sizes),
+        };
+        let debounced = DebouncedMasterPty::new(Box::new(master), Duration::from_secs(60)).unwrap();
+        debounced.resize(size(10)).unwrap();
+        debounced.resize(size(20)).unwrap();
+        drop(debounced);
+        assert_eq!(*sizes.lock().unwrap(), vec![size(20)]);
+    }
+}
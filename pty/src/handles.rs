@@ -0,0 +1,159 @@
+//! Passing the master side of a pty to another process, so that a pty
+//! that is owned by a long lived server can be used by a separate
+//! client, such as a GUI that is reloaded or reattached.
+//!
+//! Anyone that holds these handles can read everything that is shown
+//! in the terminal and can inject input into it, so only pass them to
+//! processes that are trusted to the same degree as the owner of the
+//! pty.
+use anyhow::Error;
+use filedescriptor::FileDescriptor;
+#[cfg(feature = "serde_support")]
+use serde_derive::*;
+
+/// Owned duplicates of the handles for the master side of a pty,
+/// obtained via `MasterPty::handle_set`.
+/// Reading from `read` yields the output of the child and writing to
+/// `write` sends it input, just like the reader and writer returned by
+/// `try_clone_reader` and `try_clone_writer`.  The handles are closed
+/// when the set is dropped.
+///
+/// The handles only keep the output and input streams alive; they
+/// don't keep the pty itself alive:
+///
+/// * On unix both are duplicates of the master descriptor.  The pty
+///   lasts as long as any duplicate is open, and the holder can also
+///   resize it via `TIOCSWINSZ`.
+/// * With conpty they are duplicates of the pipes connected to the
+///   pseudoconsole, which is owned by the `MasterPty` and `SlavePty`
+///   of the process that opened it.  That process must keep them alive
+///   for as long as the pty is in use, and is the only one that can
+///   resize it; once it drops them, readers see EOF.
+#[derive(Debug)]
+pub struct PtyHandleSet {
+    pub read: FileDescriptor,
+    pub write: FileDescriptor,
+}
+
+/// The raw values of a `PtyHandleSet`, for transferring to another
+/// process.  The values are only meaningful in the process that owns
+/// the handles, and that process must take ownership of them exactly
+/// once, via `PtyHandleSet::from_raw`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
+pub struct RawPtyHandles {
+    pub read: u64,
+    pub write: u64,
+}
+
+impl PtyHandleSet {
+    /// Releases ownership of the handles, returning their raw values.
+    /// On unix, descriptors can be passed to another process that is
+    /// connected via a unix domain socket as `SCM_RIGHTS` ancillary
+    /// data, after which they should be closed in this process by
+    /// passing the values to `from_raw` and dropping the result.
+    pub fn into_raw(self) -> RawPtyHandles {
+        use filedescriptor::IntoRawFileDescriptor;
+        RawPtyHandles {
+            read: self.read.into_raw_file_descriptor() as usize as u64,
+            write: self.write.into_raw_file_descriptor() as usize as u64,
+        }
+    }
+
+    /// Takes ownership of raw handle values that belong to this
+    /// process, such as those received from the process that opened
+    /// the pty.
+    ///
+    /// # Safety
+    /// The values must be open handles that are owned by the caller
+    /// and that are not owned by anything else.
+    pub unsafe fn from_raw(raw: RawPtyHandles) -> Self {
+        use filedescriptor::FromRawFileDescriptor;
+        Self {
+            read: FileDescriptor::from_raw_file_descriptor(raw.read as usize as _),
+            write: FileDescriptor::from_raw_file_descriptor(raw.write as usize as _),
+        }
+    }
+
+    /// Duplicates the handles into the process identified by `process`,
+    /// which must have been opened with `PROCESS_DUP_HANDLE` access,
+    /// and returns their values in that process.  The values must then
+    /// be communicated to that process, for example over a named pipe,
+    /// which takes ownership of them via `from_raw`.  The handles in
+    /// this set are unaffected.
+    #[cfg(windows)]
+    pub fn duplicate_into(
+        &self,
+        process: std::os::windows::io::RawHandle,
+    ) -> Result<RawPtyHandles, Error> {
+        use std::io::Error as IoError;
+        use std::os::windows::io::AsRawHandle;
+        use std::ptr;
+        use winapi::shared::minwindef::FALSE;
+        use winapi::um::handleapi::DuplicateHandle;
+        use winapi::um::processthreadsapi::GetCurrentProcess;
+        use winapi::um::winnt::{DUPLICATE_CLOSE_SOURCE, DUPLICATE_SAME_ACCESS};
+
+        let dup = |fd: &FileDescriptor| -> Result<u64, Error> {
+            let mut target = ptr::null_mut();
+            let res = unsafe {
+                DuplicateHandle(
+                    GetCurrentProcess(),
+                    fd.as_raw_handle() as _,
+                    process as _,
+                    &mut target,
+                    0,
+                    FALSE,
+                    DUPLICATE_SAME_ACCESS,
+                )
+            };
+            anyhow::ensure!(
+                res != 0,
+                "failed to duplicate handle into the target process: {}",
+                IoError::last_os_error()
+            );
+            Ok(target as usize as u64)
+        };
+
+        let read = dup(&self.read)?;
+        let write = match dup(&self.write) {
+            Ok(write) => write,
+            Err(err) => {
+                // Don't leak the first handle into the target
+                unsafe {
+                    DuplicateHandle(
+                        process as _,
+                        read as usize as _,
+                        ptr::null_mut(),
+                        ptr::null_mut(),
+                        0,
+                        FALSE,
+                        DUPLICATE_CLOSE_SOURCE,
+                    )
+                };
+                return Err(err);
+            }
+        };
+        Ok(RawPtyHandles { read, write })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{native_pty_system, PtySize};
+    use std::io::{Read, Write};
+
+    #[test]
+    fn round_trip_through_raw() {
+        let pair = native_pty_system().openpty(PtySize::default()).unwrap();
+        let raw = pair.master.handle_set().unwrap().into_raw();
+        let mut handles = unsafe { PtyHandleSet::from_raw(raw) };
+
+        // The line discipline echoes input back to the master
+        handles.write.write_all(b"hello\n").unwrap();
+        let mut buf = [0u8; 64];
+        let len = handles.read.read(&mut buf).unwrap();
+        assert!(buf[..len].starts_with(b"hello"));
+    }
+}
@@ -37,6 +37,15 @@
 //! # Ok::<(), Error>(())
 //! ```
 //!
+//! ## Async
+//!
+//! The readers and writers returned by `MasterPty` are blocking.
+//! On Windows they are backed by anonymous pipes, which don't support
+//! overlapped I/O, so the only way to avoid blocking an async runtime
+//! is to perform the I/O on a separate thread.  Wrapping the reader
+//! in `smol::Unblock` (or the equivalent offered by your runtime) does
+//! exactly that; see `examples/whoami_async.rs` for a complete example.
+//!
 //! ## ssh2
 //!
 //! If the `ssh` feature is enabled, this crate exposes an
@@ -49,12 +58,16 @@ use downcast_rs::{impl_downcast, Downcast};
 use libc;
 #[cfg(feature = "serde_support")]
 use serde_derive::*;
-use std::io::Result as IoResult;
+use std::io::{BufReader, Result as IoResult, Write};
 #[cfg(windows)]
 use std::os::windows::prelude::{AsRawHandle, RawHandle};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
 
+pub mod channel;
+pub use channel::spawn_channel_reader;
 pub mod cmdbuilder;
-pub use cmdbuilder::CommandBuilder;
+pub use cmdbuilder::{CommandBuilder, PriorityClass, StdioMode};
 
 #[cfg(unix)]
 pub mod unix;
@@ -66,6 +79,135 @@ pub mod ssh;
 
 pub mod serial;
 
+pub mod debounce;
+pub use debounce::DebouncedMasterPty;
+pub mod handles;
+pub use handles::{PtyHandleSet, RawPtyHandles};
+pub mod poller;
+pub use poller::{PtyPoller, ReadableToken};
+pub mod pool;
+pub use pool::{BufferPool, PooledBuffer, PooledReader};
+pub mod split;
+pub use split::{PtyReader, PtyWriter};
+pub mod tap;
+pub use tap::OutputTap;
+
+/// Errors produced by the pty implementations in this crate.
+/// The trait methods return `anyhow::Error` so that other crates are
+/// free to implement them with their own error types; when the error
+/// originated here it can be recovered using
+/// `err.downcast_ref::<PtyError>()`.
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum PtyError {
+    #[error("failed to openpty")]
+    OpenPty(#[source] std::io::Error),
+    #[error("failed to create a pipe")]
+    Pipe(#[source] filedescriptor::Error),
+    #[error("this system does not support conpty.  Windows 10 October 2018 or newer is required")]
+    ConPtyUnavailable,
+    #[error("failed to resize pty to {}x{}", size.cols, size.rows)]
+    Resize {
+        size: PtySize,
+        #[source]
+        source: std::io::Error,
+    },
+    /// Resizing failed because the pty has already been torn down, for
+    /// example because the process hosting the pseudoconsole exited.
+    /// A terminal that resizes a tab whose child has just exited can
+    /// safely ignore this.
+    #[error("unable to resize the pty because it has already been closed")]
+    PtyClosed(#[source] std::io::Error),
+    #[error("timed out after {0:?} waiting for the pty to open")]
+    OpenTimeout(Duration),
+    #[error("failed to spawn {command}")]
+    Spawn {
+        command: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("failed to update the terminal attributes")]
+    Termios(#[source] std::io::Error),
+    #[error("requested {value} {dimension} is outside the supported range of 1 to {max}")]
+    InvalidSize {
+        dimension: &'static str,
+        value: u16,
+        max: u16,
+    },
+    #[error(
+        "the command line is {len} characters long, which exceeds the \
+         Windows limit of 32767 characters"
+    )]
+    CommandLineTooLong { len: usize },
+    #[error(
+        "failed to create a pipe because the system is out of handles or \
+         memory; try closing some ptys or other applications"
+    )]
+    ResourceExhausted(#[source] filedescriptor::Error),
+    #[error("unable to spawn {program:?} because {reason}")]
+    InvalidProgram {
+        program: std::ffi::OsString,
+        reason: String,
+    },
+    #[error(
+        "unable to spawn the command because its working directory {cwd:?} is not a directory"
+    )]
+    InvalidCwd { cwd: std::ffi::OsString },
+    /// The pty doesn't expose its handles via `MasterPty::handle_set`
+    #[error("this pty cannot be shared with another process")]
+    HandlesUnavailable,
+    /// Duplicating a handle failed, which usually means that the
+    /// process has run out of handles; `handle` names the handle and
+    /// `operation` what it was being duplicated for.
+    #[error("failed to duplicate the {handle} handle in order to {operation}")]
+    HandleClone {
+        handle: &'static str,
+        operation: &'static str,
+        #[source]
+        source: filedescriptor::Error,
+    },
+}
+
+impl PtyError {
+    /// Classifies a failure to create a pipe.  Running out of handles
+    /// or memory is by far the most common cause, for example when a
+    /// very large number of ptys are open, so that gets its own variant.
+    pub(crate) fn pipe(err: filedescriptor::Error) -> Self {
+        #[cfg(unix)]
+        const EXHAUSTED: &[i32] = &[libc::EMFILE, libc::ENFILE, libc::ENOMEM];
+        #[cfg(windows)]
+        const EXHAUSTED: &[i32] = &[
+            winapi::shared::winerror::ERROR_NO_SYSTEM_RESOURCES as i32,
+            winapi::shared::winerror::ERROR_NOT_ENOUGH_MEMORY as i32,
+        ];
+        let exhausted = match &err {
+            filedescriptor::Error::Pipe(io) => io
+                .raw_os_error()
+                .map(|code| EXHAUSTED.contains(&code))
+                .unwrap_or(false),
+            _ => false,
+        };
+        if exhausted {
+            Self::ResourceExhausted(err)
+        } else {
+            Self::Pipe(err)
+        }
+    }
+
+    /// Describes a failure to duplicate `handle` in order to
+    /// `operation`, for use with `map_err` on the result of `try_clone`
+    pub(crate) fn handle_clone(
+        handle: &'static str,
+        operation: &'static str,
+    ) -> impl FnOnce(filedescriptor::Error) -> Self {
+        move |source| Self::HandleClone {
+            handle,
+            operation,
+            source,
+        }
+    }
+}
+
 /// Represents the size of the visible display area in the pty
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
@@ -94,25 +236,290 @@ impl Default for PtySize {
 }
 
 /// Represents the master/control end of the pty
+///
+/// Writes to the pty block when the slave side isn't consuming its input
+/// quickly enough.  A blocked write only ties up the writing thread:
+/// readers obtained via `try_clone_reader` are independent handles and
+/// can continue to read while a write is pending, as can `resize` and
+/// `get_size`.  A child that is blocked writing its output may stop
+/// reading its input, so the output must be drained on a separate
+/// thread from the one writing to the pty in order to avoid deadlock.
 pub trait MasterPty: std::io::Write {
     /// Inform the kernel and thus the child process that the window resized.
     /// It will update the winsize information maintained by the kernel,
     /// and generate a signal for the child to notice and update its state.
+    /// With conpty, resizing after the pseudoconsole has shut down fails
+    /// with `PtyError::PtyClosed`.
     fn resize(&self, size: PtySize) -> Result<(), Error>;
+    /// Resize the pty and then send Ctrl-L (form feed) to the slave,
+    /// which asks most shells and full screen applications to clear the
+    /// screen and redraw.  This is useful with conpty, which reflows
+    /// its buffer on resize and can leave duplicated content behind.
+    /// Applications that don't treat Ctrl-L specially will see it as
+    /// regular input, so only use this when the foreground program is
+    /// known to handle it.
+    /// If the child is part way through drawing a frame when the resize
+    /// happens, the remainder of that frame is rendered at the new size
+    /// and may be garbled, but the Ctrl-L is queued behind it in the
+    /// input stream, so the child's redraw will replace it.
+    fn resize_clearing(&self, size: PtySize) -> Result<(), Error> {
+        self.resize(size)?;
+        self.try_clone_writer()?.write_all(b"\x0c")?;
+        Ok(())
+    }
+    /// Sends SIGWINCH to the foreground process group of the pty.
+    /// The kernel already does this when `resize` changes the size,
+    /// so this is only needed for programs that failed to notice,
+    /// or to prompt a redraw without changing the size.
+    /// This is a no-op on Windows, and for ptys that don't have a
+    /// local process group.
+    fn signal_window_change(&self) -> Result<(), Error> {
+        #[cfg(unix)]
+        if let Some(pgrp) = self.process_group_leader() {
+            if unsafe { libc::killpg(pgrp, libc::SIGWINCH) } != 0 {
+                anyhow::bail!(
+                    "failed to send SIGWINCH to process group {}: {}",
+                    pgrp,
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+        Ok(())
+    }
     /// Retrieves the size of the pty as known by the kernel
     fn get_size(&self) -> Result<PtySize, Error>;
+    /// Retrieves the size of the screen buffer that backs the pty, for
+    /// protocols that need to know the full buffer dimensions rather
+    /// than just the visible area.
+    /// None of the implementations in this crate maintain a buffer that
+    /// is larger than the visible area: a unix pty has no buffer at
+    /// all, and conpty sizes the console screen buffer of the child to
+    /// match the pseudoconsole, keeping no scrollback of its own, and
+    /// offers no API to query it.  The default implementation
+    /// therefore returns `get_size`, so that programs that query the
+    /// window size via escape sequences or via the console APIs get
+    /// consistent answers.
+    fn buffer_size(&self) -> Result<PtySize, Error> {
+        self.get_size()
+    }
     /// Obtain a readable handle; output from the slave(s) is readable
     /// via this stream.
+    /// The stream reports EOF once nothing can write to the slave side:
+    /// on unix that means that the slave and all processes spawned into
+    /// it have closed their descriptors, and with conpty it means that
+    /// both the master and the slave have been dropped, because conpty
+    /// keeps its output pipe open after the child exits.  Make sure to
+    /// drop them once the child has exited, otherwise reading to the
+    /// end of the stream will block forever.
+    /// Each call returns an independent duplicate of the output handle,
+    /// while the original is retained by the `MasterPty`, so dropping
+    /// one reader doesn't affect other readers, and a new reader can be
+    /// obtained after a previous one was dropped.  Readers that are used
+    /// at the same time compete for the same output: each piece of
+    /// output is returned to only one of them.
     fn try_clone_reader(&self) -> Result<Box<dyn std::io::Read + Send>, Error>;
+    /// Obtain a buffered readable handle.  This is a convenience for
+    /// wrapping `try_clone_reader` in a `BufReader`, which is useful when
+    /// consuming the output line by line via `BufRead::lines` or
+    /// `BufRead::read_line`, and avoids making a read call for each
+    /// byte in naive read loops.
+    fn buffered_reader(&self) -> Result<BufReader<Box<dyn std::io::Read + Send>>, Error> {
+        Ok(BufReader::new(self.try_clone_reader()?))
+    }
+    /// Obtain a readable handle, as per `try_clone_reader`, that also
+    /// retains the most recent `capacity` bytes that were read through
+    /// it.  The retained output is available from the returned
+    /// `OutputTap`, which is useful for capturing what the terminal
+    /// showed just before a program misbehaved.
+    fn with_tap(
+        &self,
+        capacity: usize,
+    ) -> Result<(Box<dyn std::io::Read + Send>, OutputTap), Error> {
+        let (reader, tap) = tap::TapReader::new(self.try_clone_reader()?, capacity);
+        Ok((Box::new(reader), tap))
+    }
+    /// Obtain a reader, as per `try_clone_reader`, that reads output
+    /// into buffers taken from `pool` rather than into a buffer supplied
+    /// by the caller.  This suits a terminal that passes each chunk of
+    /// output on to another thread, as the buffer is returned to the
+    /// pool once the chunk is dropped instead of each chunk being copied
+    /// into a newly allocated `Vec`.
+    fn pooled_reader(
+        &self,
+        pool: BufferPool,
+    ) -> Result<PooledReader<Box<dyn std::io::Read + Send>>, Error> {
+        Ok(PooledReader::new(self.try_clone_reader()?, pool))
+    }
+    /// Reads output, as per `try_clone_reader`, on a background thread
+    /// and delivers it via a channel that holds at most `capacity`
+    /// chunks.  When the receiver falls behind, the thread stops
+    /// reading and the child eventually blocks writing its output, so a
+    /// flood of output costs latency rather than unbounded memory.
+    /// See `spawn_channel_reader` for how to choose the capacity.
+    fn channel_reader(&self, capacity: usize) -> Result<Receiver<IoResult<Vec<u8>>>, Error> {
+        Ok(spawn_channel_reader(self.try_clone_reader()?, capacity)?)
+    }
+    /// Reads output from the slave(s) directly from the master; this
+    /// is what the `Read` impl on `dyn MasterPty` calls.  It is a
+    /// convenience for simple synchronous code that would otherwise
+    /// have to keep a separate reader around.  Reads are serialized by
+    /// a lock that is separate from the one used for resizing, just
+    /// like writes.  Reading from the master while a reader obtained
+    /// via `try_clone_reader` is also in use is not supported: the two
+    /// would compete for the same output.
+    /// The default implementation returns an `Unsupported` error.
+    fn read_output(&mut self, _buf: &mut [u8]) -> IoResult<usize> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "reading directly from this pty is not supported; use try_clone_reader",
+        ))
+    }
+    /// Reads whatever output is currently available, without blocking,
+    /// and returns the number of bytes read.  Returns 0 if no output is
+    /// pending, which means that EOF cannot be distinguished from there
+    /// being nothing to read; use `Child::try_wait` or a blocking reader
+    /// to detect that the child has finished.
+    /// This is intended for render loops that need to do other work
+    /// in between consuming output.  If other threads read from the pty
+    /// at the same time they may consume the pending output first, in
+    /// which case this can block until more arrives.
+    /// The default implementation returns an error.
+    fn read_available(&self, _buf: &mut [u8]) -> Result<usize, Error> {
+        anyhow::bail!("non-blocking reads are not supported by this pty")
+    }
+    /// Returns a token that can be registered with a `PtyPoller` to
+    /// wait for output to become available on this pty, which allows
+    /// a single thread to service many ptys.  Once it reports the pty
+    /// as ready, use `read_available` to consume the output.
+    /// The default implementation returns an error.
+    fn readable_token(&self) -> Result<ReadableToken, Error> {
+        anyhow::bail!("waiting for readability is not supported by this pty")
+    }
     /// Obtain a writable handle; writing to it will send data to the
     /// slave end.  This is equivalent to the Write impl on MasterPty
     /// itself, but allows splitting it off into a separate object.
     fn try_clone_writer(&self) -> Result<Box<dyn std::io::Write + Send>, Error>;
+    /// Returns owned duplicates of the handles used to read from and
+    /// write to the pty, which can be passed to another process; see
+    /// `PtyHandleSet` for the constraints on doing so.
+    /// The default implementation returns `PtyError::HandlesUnavailable`.
+    fn handle_set(&self) -> Result<PtyHandleSet, Error> {
+        Err(PtyError::HandlesUnavailable.into())
+    }
+    /// Writes the whole of `buf` to the slave end, looping over partial
+    /// writes.  This is intended for sending large amounts of input,
+    /// such as a bracketed paste of a big clipboard, which frequently
+    /// won't fit into the input buffer of the pty in one go.
+    /// Interrupted writes are retried, and if the input buffer is
+    /// temporarily full (`WouldBlock`) this waits briefly for the child
+    /// to consume some of it before trying again.
+    fn write_all_pty(&self, buf: &[u8]) -> Result<(), Error> {
+        use std::io::ErrorKind;
+
+        let mut writer = self.try_clone_writer()?;
+        let mut buf = buf;
+        while !buf.is_empty() {
+            match writer.write(buf) {
+                Ok(0) => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::WriteZero,
+                        "failed to write the whole buffer to the pty",
+                    )
+                    .into())
+                }
+                Ok(len) => buf = &buf[len..],
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+    /// Signals end-of-file to the program that is reading from the pty,
+    /// so that its next read of its stdin returns 0, just like pressing
+    /// the EOF key in a terminal.
+    ///
+    /// The input of a pty is a terminal rather than a pipe, so it
+    /// doesn't reach EOF when handles to it are closed: holding on to
+    /// the slave, or to writers obtained via `try_clone_writer`, does
+    /// not prevent EOF, and dropping them doesn't produce it.  Instead
+    /// the EOF character is sent, which is only interpreted while the
+    /// terminal is in canonical (cooked) mode:
+    ///
+    /// * On unix this writes the `VEOF` character of the tty, which is
+    ///   `^D` unless it was changed, and fails if the tty is in raw
+    ///   mode.  If part of a line has been written, that partial line
+    ///   is delivered first and a second call is needed to produce EOF.
+    /// * With conpty and winpty this writes `^Z` followed by a carriage
+    ///   return, which the console turns into a zero length read for
+    ///   programs that read their input via `ReadFile`.
+    ///
+    /// The default implementation writes `^D`, which suits ptys that
+    /// are hosted by a remote unix system.
+    fn send_eof(&self) -> Result<(), Error> {
+        self.write_all_pty(b"\x04")
+    }
 
     /// If applicable to the type of the tty, return the local process id
     /// of the process group or session leader
     #[cfg(unix)]
     fn process_group_leader(&self) -> Option<libc::pid_t>;
+
+    /// Returns the file descriptor of the master side of the pty, if
+    /// applicable, for integration with other APIs such as `epoll` or
+    /// `mio`.  It becomes readable when there is output to read.
+    /// The same descriptor is used for both reading and writing; the
+    /// readers and writers obtained via `try_clone_reader` and
+    /// `try_clone_writer` are duplicates of it that share its file
+    /// status flags, so making it non-blocking, as event loops usually
+    /// do, makes them non-blocking too.
+    /// The descriptor remains owned by the MasterPty: the caller must not
+    /// close it, and must not use it after the MasterPty is dropped.
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        None
+    }
+
+    /// Returns the raw (read, write) handles of the master side of the
+    /// pty, if applicable, for integration with other APIs such as an
+    /// I/O completion port.
+    /// The handles remain owned by the MasterPty: the caller must not
+    /// close them, and must not use them after the MasterPty is dropped.
+    #[cfg(windows)]
+    fn as_raw_handles(&self) -> Option<(RawHandle, RawHandle)> {
+        None
+    }
+}
+
+impl dyn MasterPty + Send {
+    /// Consumes the master and returns a reader and a writer that each
+    /// own their handle, for when reading and writing happen on
+    /// separate threads.  Unlike the boxed objects returned by
+    /// `try_clone_reader` and `try_clone_writer`, they are concrete
+    /// types, and with the native ptys they read and write the
+    /// underlying handles directly.
+    /// The master is retained by the writer and is available via
+    /// `PtyWriter::master`, so that the pty can still be resized.
+    /// On Windows the reader doesn't see EOF until the writer has been
+    /// dropped; see `PtyReader`.
+    pub fn split(self: Box<Self>) -> Result<(PtyReader, PtyWriter), Error> {
+        split::split(self)
+    }
+}
+
+impl std::io::Read for dyn MasterPty {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.read_output(buf)
+    }
+}
+
+impl std::io::Read for dyn MasterPty + Send {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.read_output(buf)
+    }
 }
 
 /// Represents a child process spawned into the pty.
@@ -126,8 +533,177 @@ pub trait Child: std::fmt::Debug + ChildKiller {
     /// Blocks execution until the child process has completed,
     /// yielding its exit status.
     fn wait(&mut self) -> IoResult<ExitStatus>;
+    /// Blocks execution until either the child process has completed
+    /// or the specified duration has elapsed.
+    /// Returns None if the child has not yet terminated,
+    /// else returns its exit status.
+    /// The default implementation polls `try_wait`.
+    fn wait_timeout(&mut self, timeout: Duration) -> IoResult<Option<ExitStatus>> {
+        let deadline = match Instant::now().checked_add(timeout) {
+            Some(deadline) => deadline,
+            None => return self.wait().map(Some),
+        };
+        loop {
+            if let Some(status) = self.try_wait()? {
+                return Ok(Some(status));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            std::thread::sleep((deadline - now).min(Duration::from_millis(50)));
+        }
+    }
+    /// Returns true if the child has not yet terminated.
+    /// Unlike `try_wait`, this doesn't collect the exit status and has
+    /// no side effects, so it is cheap enough to call from a render loop.
+    /// Implementations that cannot cheaply determine this, such as
+    /// those for remote processes, use the default implementation
+    /// which always returns true; use `try_wait` for those.
+    fn is_alive(&self) -> bool {
+        true
+    }
+    /// Asks the child to exit, giving it up to `grace` to do so before
+    /// forcibly terminating it, and returns its exit status.  This is
+    /// what a terminal should do when a tab is closed, as it gives the
+    /// child a chance to save its state and clean up.
+    /// The child is first sent `Signal::Terminate` on unix, and
+    /// `Signal::Interrupt` on Windows, where `Terminate` is not a
+    /// request but an immediate `TerminateProcess`.  If the child is
+    /// still running once `grace` has elapsed, or if the soft signal
+    /// couldn't be delivered, it is sent `Signal::Kill`, falling back
+    /// to `kill` if that is unsupported, and then waited for.
+    /// A child that has already exited is just waited for.
+    ///
+    /// On Windows the interrupt is delivered by writing `^C` into the
+    /// pseudoconsole, so every process attached to that console is
+    /// interrupted, not just the child.  After `release_input` the
+    /// interrupt can't be delivered, so the child is killed immediately
+    /// without being given `grace` to exit.
+    fn kill_gracefully(&mut self, grace: Duration) -> IoResult<ExitStatus> {
+        if let Some(status) = self.try_wait()? {
+            return Ok(status);
+        }
+        let soft = if cfg!(windows) {
+            Signal::Interrupt
+        } else {
+            Signal::Terminate
+        };
+        match self.send_signal(soft) {
+            Ok(()) => {
+                if let Some(status) = self.wait_timeout(grace)? {
+                    return Ok(status);
+                }
+            }
+            Err(err) => log::debug!("failed to send {:?}: {}", soft, err),
+        }
+        if let Err(err) = self.send_signal(Signal::Kill) {
+            // It may have exited in the meantime
+            if let Some(status) = self.try_wait()? {
+                return Ok(status);
+            }
+            if err.kind() != std::io::ErrorKind::Unsupported {
+                return Err(err);
+            }
+            self.kill()?;
+        }
+        self.wait()
+    }
+    /// Interrupts the child as though the user had pressed Ctrl-C.
+    /// This is `send_signal(Signal::Interrupt)`: on unix the child is
+    /// sent `SIGINT`, and with conpty a `^C` is written to the input of
+    /// the pseudoconsole, which raises `CTRL_C_EVENT` in all of the
+    /// processes attached to it.  `GenerateConsoleCtrlEvent` is not
+    /// used for this because `CTRL_C_EVENT` cannot be targeted at a
+    /// process group, and because we don't share a console with the
+    /// child.
+    fn send_ctrl_c(&mut self) -> IoResult<()> {
+        self.send_signal(Signal::Interrupt)
+    }
+    /// Sends the child the equivalent of Ctrl-Break.
+    /// On Windows this raises `CTRL_BREAK_EVENT` in the process group
+    /// of the child via `GenerateConsoleCtrlEvent`.  That requires the
+    /// child to have been spawned with `CommandBuilder::new_process_group`
+    /// so that it leads its own group, and only works if the calling
+    /// process is attached to the same console as the child, for example
+    /// via `AttachConsole`; the console of a conpty is private to it, so
+    /// this fails for conpty children unless that has been arranged.
+    /// On unix this sends `SIGQUIT`, which is what Ctrl-\ generates.
+    fn send_ctrl_break(&mut self) -> IoResult<()> {
+        self.send_signal(Signal::Quit)
+    }
+    /// Resumes a child that was started suspended via
+    /// `CommandBuilder::suspended`.  This is only supported on Windows.
+    fn resume(&mut self) -> IoResult<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "resume is not supported by this Child",
+        ))
+    }
+    /// Returns the handle of the main thread of the child process, if
+    /// it was retained via `CommandBuilder::retain_main_thread` or
+    /// `CommandBuilder::suspended`.  The handle remains owned by the
+    /// Child and is closed when it is dropped.
+    #[cfg(windows)]
+    fn main_thread_handle(&self) -> Option<RawHandle> {
+        None
+    }
+    /// Takes the reader for the child's stderr, if it was configured
+    /// via `CommandBuilder::stderr(StdioMode::Pipe)`.
+    /// Returns None if stderr isn't a pipe or if it was already taken.
+    fn take_stderr(&mut self) -> Option<Box<dyn std::io::Read + Send>> {
+        None
+    }
+    /// Drops the Child's reference to the input side of the pty, which
+    /// it uses to deliver `^C` for `send_ctrl_c` on Windows.  After
+    /// this, `send_ctrl_c` returns an `Unsupported` error.  Killers
+    /// obtained via `clone_killer` before calling this are unaffected.
+    /// The reference is weak, so it never keeps the pty alive: once
+    /// the master and slave have been dropped, `send_ctrl_c` fails
+    /// regardless.
+    ///
+    /// This is not needed for the child to see EOF on its stdin; use
+    /// `MasterPty::send_eof` for that.
+    /// The default implementation does nothing, as no other Child holds
+    /// such a reference.
+    fn release_input(&mut self) {}
+    /// Releases the child, closing our handle on it without affecting
+    /// the process, which is left to run to completion on its own.
+    /// This overrides `CommandBuilder::kill_on_drop` and, on Windows,
+    /// `CommandBuilder::kill_on_drop_tree`, so that the process is
+    /// guaranteed not to be terminated as a consequence of releasing it.
+    ///
+    /// On unix nothing waits for the process once it has been detached,
+    /// so when it exits it remains a zombie until this process exits,
+    /// unless this process reaps it some other way, such as by ignoring
+    /// `SIGCHLD`.
+    fn detach(self: Box<Self>) {}
+    /// Returns the current working directory of the child, such as for
+    /// showing it in the title of a tab.  This reflects the directory
+    /// that the child itself has changed to, not any of its own
+    /// children; for a shell running a command, that is the directory
+    /// of the shell.
+    ///
+    /// This is read from `/proc/<pid>/cwd` on Linux, via
+    /// `proc_pidinfo` on macOS, and from the process environment block
+    /// of the process on Windows, which requires a 64-bit process to
+    /// query 64-bit children.
+    /// Returns None if the directory cannot be determined, such as when
+    /// the child has exited, when we lack permission to inspect it, or
+    /// on other platforms.  The default implementation returns None,
+    /// which is appropriate for remote processes.
+    fn current_working_dir(&self) -> Option<std::path::PathBuf> {
+        None
+    }
     /// Returns the process identifier of the child process,
-    /// if applicable
+    /// if applicable.
+    /// The identifier is recorded when the child is spawned, so it is
+    /// unaffected by duplicating or closing handles to the process, and
+    /// remains available after the child has exited and been waited
+    /// for.  That makes it suitable for identifying the child between
+    /// a server and a client that was passed a duplicate of its handle;
+    /// note though that the system may reuse it for a new process once
+    /// the child has exited and all handles to it have been closed.
     fn process_id(&self) -> Option<u32>;
     /// Returns the process handle of the child process, if applicable.
     /// Only available on Windows.
@@ -144,13 +720,226 @@ pub trait ChildKiller: std::fmt::Debug {
     /// to send it signals independently from a thread that may be
     /// blocked in `.wait`.
     fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync>;
+
+    /// Deliver a signal to the child process.
+    /// The default implementation maps `Terminate` and `Kill` to `kill`
+    /// and returns an `Unsupported` error for any other signal.
+    fn send_signal(&mut self, signal: Signal) -> IoResult<()> {
+        match signal {
+            Signal::Terminate | Signal::Kill => self.kill(),
+            _ => Err(unsupported_signal(signal)),
+        }
+    }
+}
+
+/// Represents a signal that can be delivered to a child process
+/// via `ChildKiller::send_signal`.
+/// On Windows only `Interrupt`, `Terminate` and `Kill` have any
+/// effect; sending any other signal returns an `Unsupported` error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// `SIGHUP` on unix.  Not supported on Windows.
+    Hangup,
+    /// `SIGINT` on unix.
+    /// On Windows, a `^C` is written to the input of the pseudoconsole,
+    /// which causes conpty to raise `CTRL_C_EVENT` in the processes
+    /// that are attached to it.
+    Interrupt,
+    /// `SIGQUIT` on unix.  Not supported on Windows.
+    Quit,
+    /// `SIGTERM` on unix, `TerminateProcess` on Windows
+    Terminate,
+    /// `SIGKILL` on unix, `TerminateProcess` on Windows
+    Kill,
+}
+
+pub(crate) fn unsupported_signal(signal: Signal) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("sending {:?} is not supported for this child", signal),
+    )
+}
+
+/// Tests whether the child with the specified pid has exited, without
+/// reaping it, by passing WNOWAIT to waitid(2)
+#[cfg(unix)]
+fn unix_child_is_alive(pid: u32) -> bool {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let result = unsafe {
+        libc::waitid(
+            libc::P_PID,
+            pid as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+        )
+    };
+    // With WNOHANG, si_pid is left as zero if the child hasn't exited.
+    // An error means that it has already been reaped.
+    result == 0 && siginfo_pid(&info) == 0
+}
+
+/// Returns the current directory of the process with the specified pid
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn unix_process_cwd(pid: u32) -> Option<std::path::PathBuf> {
+    std::fs::read_link(format!("/proc/{}/cwd", pid)).ok()
+}
+
+/// Returns the current directory of the process with the specified pid
+#[cfg(target_os = "macos")]
+fn unix_process_cwd(pid: u32) -> Option<std::path::PathBuf> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut pathinfo: libc::proc_vnodepathinfo = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of_val(&pathinfo) as libc::c_int;
+    let ret = unsafe {
+        libc::proc_pidinfo(
+            pid as _,
+            libc::PROC_PIDVNODEPATHINFO,
+            0,
+            &mut pathinfo as *mut _ as *mut _,
+            size,
+        )
+    };
+    if ret != size {
+        return None;
+    }
+    // libc declares vip_path as `[[c_char; 32]; 32]` rather than as
+    // `[c_char; MAXPATHLEN]`, for the sake of old compilers
+    let vip_path = unsafe {
+        std::slice::from_raw_parts(
+            pathinfo.pvi_cdir.vip_path.as_ptr() as *const u8,
+            libc::MAXPATHLEN as usize,
+        )
+    };
+    let nul = vip_path.iter().position(|&c| c == 0)?;
+    Some(OsStr::from_bytes(&vip_path[..nul]).into())
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "linux", target_os = "android", target_os = "macos"))
+))]
+fn unix_process_cwd(_pid: u32) -> Option<std::path::PathBuf> {
+    None
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn siginfo_pid(info: &libc::siginfo_t) -> libc::pid_t {
+    unsafe { info.si_pid() }
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn siginfo_pid(info: &libc::siginfo_t) -> libc::pid_t {
+    info.si_pid
+}
+
+/// Tests whether the process handle has not yet been signalled,
+/// which happens when the process exits
+#[cfg(windows)]
+pub(crate) fn windows_handle_is_alive(handle: RawHandle) -> bool {
+    use winapi::shared::winerror::WAIT_TIMEOUT;
+    use winapi::um::synchapi::WaitForSingleObject;
+    unsafe { WaitForSingleObject(handle as _, 0) == WAIT_TIMEOUT }
+}
+
+#[cfg(unix)]
+fn send_unix_signal(pid: u32, signal: Signal) -> IoResult<()> {
+    let signo = match signal {
+        Signal::Hangup => libc::SIGHUP,
+        Signal::Interrupt => libc::SIGINT,
+        Signal::Quit => libc::SIGQUIT,
+        Signal::Terminate => libc::SIGTERM,
+        Signal::Kill => libc::SIGKILL,
+    };
+    let result = unsafe { libc::kill(pid as i32, signo) };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
 }
 
 /// Represents the slave side of a pty.
 /// Can be used to spawn processes into the pty.
 pub trait SlavePty {
-    /// Spawns the command specified by the provided CommandBuilder
+    /// Spawns the command specified by the provided CommandBuilder.
+    /// The slave remains usable afterwards, so this can be called again
+    /// to spawn another command into the same pty, for example to
+    /// respawn a shell once the previous one has exited.  The pty
+    /// remains open for as long as either the master or the slave
+    /// is alive.  The slave is not the input of the child in the way
+    /// that the write end of a pipe would be, so keeping it alive
+    /// doesn't prevent the child from seeing EOF; see
+    /// `MasterPty::send_eof`.
     fn spawn_command(&self, cmd: CommandBuilder) -> Result<Box<dyn Child + Send + Sync>, Error>;
+
+    /// Spawns the command specified by the provided CommandBuilder,
+    /// consuming the slave in the process.
+    /// This is intended for the common case where only a single
+    /// command will be spawned into the pty; implementations can
+    /// move their handles into the child rather than duplicating them.
+    /// Use `spawn_command` if you need to spawn more than once.
+    fn spawn_command_once(
+        self: Box<Self>,
+        cmd: CommandBuilder,
+    ) -> Result<Box<dyn Child + Send + Sync>, Error> {
+        self.spawn_command(cmd)
+    }
+
+    /// Returns the path of the slave tty device, such as `/dev/pts/3`,
+    /// which is useful for session accounting and logging.
+    /// Returns None if the pty has no such path, which is always the
+    /// case on Windows.
+    fn name(&self) -> Option<String> {
+        None
+    }
+
+    /// Reads the termios settings of the slave tty, passes them to `f`
+    /// for modification and then applies the result immediately.
+    /// This is the low level building block for `set_raw` and
+    /// `set_cooked`.
+    #[cfg(unix)]
+    fn with_termios(&self, _f: &mut dyn FnMut(&mut libc::termios)) -> Result<(), Error> {
+        anyhow::bail!("this pty does not support changing termios settings")
+    }
+
+    /// Puts the slave tty into raw mode: input is made available
+    /// byte-by-byte, without echo, line editing or signal generation.
+    /// On Windows this is a no-op.
+    fn set_raw(&self) -> Result<(), Error> {
+        #[cfg(unix)]
+        {
+            self.with_termios(&mut |t: &mut libc::termios| unsafe { libc::cfmakeraw(t) })
+        }
+        #[cfg(windows)]
+        {
+            Ok(())
+        }
+    }
+
+    /// Puts the slave tty into cooked (canonical) mode: input is
+    /// echoed and line buffered, and the interrupt characters generate
+    /// signals.  This matches the settings of a freshly opened pty.
+    /// On Windows this is a no-op.
+    fn set_cooked(&self) -> Result<(), Error> {
+        #[cfg(unix)]
+        {
+            self.with_termios(&mut make_cooked)
+        }
+        #[cfg(windows)]
+        {
+            Ok(())
+        }
+    }
+}
+
+/// Enables the line discipline settings that cfmakeraw turns off
+#[cfg(unix)]
+fn make_cooked(t: &mut libc::termios) {
+    t.c_iflag &= !(libc::INLCR | libc::IGNCR);
+    t.c_iflag |= libc::ICRNL | libc::IXON | libc::BRKINT;
+    t.c_oflag |= libc::OPOST | libc::ONLCR;
+    t.c_lflag |= libc::ECHO | libc::ECHOE | libc::ECHOK | libc::ICANON | libc::ISIG | libc::IEXTEN;
 }
 
 /// Represents the exit status of a child process.
@@ -158,12 +947,29 @@ pub trait SlavePty {
 pub struct ExitStatus {
     code: u32,
     signal: Option<String>,
+    signal_number: Option<i32>,
+    terminated: bool,
 }
 
 impl ExitStatus {
     /// Construct an ExitStatus from a process return code
     pub fn with_exit_code(code: u32) -> Self {
-        Self { code, signal: None }
+        Self {
+            code,
+            signal: None,
+            signal_number: None,
+            terminated: false,
+        }
+    }
+
+    /// Construct an ExitStatus for a process that didn't exit of its
+    /// own accord but was forcibly terminated, for example via
+    /// `TerminateProcess`, which made it exit with `code`
+    pub fn with_terminated_exit_code(code: u32) -> Self {
+        Self {
+            terminated: true,
+            ..Self::with_exit_code(code)
+        }
     }
 
     /// Construct an ExitStatus from a signal name
@@ -171,6 +977,8 @@ impl ExitStatus {
         Self {
             code: 1,
             signal: Some(signal.to_string()),
+            signal_number: None,
+            terminated: true,
         }
     }
 
@@ -182,10 +990,94 @@ impl ExitStatus {
         }
     }
 
-    /// Returns the exit code that this ExitStatus was constructed with
+    /// Returns the exit code that this ExitStatus was constructed with.
+    /// See `code` for a variant that distinguishes termination by a
+    /// signal, in the manner of `std::process::ExitStatus::code`.
     pub fn exit_code(&self) -> u32 {
         self.code
     }
+
+    /// Returns the exit code of the process, or None if it was
+    /// terminated by a signal.  This matches
+    /// `std::process::ExitStatus::code`.
+    pub fn code(&self) -> Option<i32> {
+        match self.signal {
+            None => Some(self.code as i32),
+            Some(_) => None,
+        }
+    }
+
+    /// Returns the number of the signal that terminated the process.
+    /// This is only known on unix, for processes that were spawned
+    /// locally; it is always None on Windows, and None for statuses
+    /// constructed from a signal name via `with_signal`.
+    pub fn signal(&self) -> Option<i32> {
+        self.signal_number
+    }
+
+    /// Returns true if the process was killed rather than exiting on
+    /// its own, which a shell may want to report as an abnormal
+    /// termination.
+    /// On unix this is true if the process was terminated by a signal,
+    /// whether that was sent via `ChildKiller::kill` or by someone else.
+    /// Windows has no equivalent of a signal, so there this is true if
+    /// the process was terminated via `ChildKiller::kill` or
+    /// `send_signal` on its `Child`, or on a killer cloned from it, and
+    /// then exited with the code that we terminate processes with.
+    /// A process terminated by some other means is indistinguishable
+    /// from one that exited with the same code.
+    pub fn was_terminated(&self) -> bool {
+        self.terminated
+    }
+
+    /// Returns a description of the status that is suitable for showing
+    /// to the user, such as "Segmentation fault" when a unix process
+    /// was terminated by `SIGSEGV`.
+    /// Processes on Windows that crash or are interrupted exit with an
+    /// `NTSTATUS` code, which is recognized for the common cases, for
+    /// example `0xC0000005` is described as
+    /// "Access violation (0xC0000005)"; other large codes are shown in
+    /// hex, and small ones in decimal.
+    pub fn describe(&self) -> String {
+        if let Some(signal) = &self.signal {
+            return signal.clone();
+        }
+        if self.code == 0 {
+            return "Success".to_string();
+        }
+        if let Some(desc) = describe_ntstatus(self.code) {
+            return format!("{} (0x{:08X})", desc, self.code);
+        }
+        if self.code >= 0x8000_0000 {
+            format!("Exited with code 0x{:08X}", self.code)
+        } else {
+            format!("Exited with code {}", self.code)
+        }
+    }
+}
+
+/// Describes the NTSTATUS codes that processes commonly exit with
+fn describe_ntstatus(code: u32) -> Option<&'static str> {
+    Some(match code {
+        0x8000_0003 => "Breakpoint",
+        0xC000_0005 => "Access violation",
+        0xC000_0006 => "In-page I/O error",
+        0xC000_0008 => "Invalid handle",
+        0xC000_0017 => "Out of memory",
+        0xC000_001D => "Illegal instruction",
+        0xC000_008E => "Floating point division by zero",
+        0xC000_0094 => "Integer division by zero",
+        0xC000_0095 => "Integer overflow",
+        0xC000_0096 => "Privileged instruction",
+        0xC000_00FD => "Stack overflow",
+        0xC000_013A => "Interrupted by Ctrl-C",
+        0xC000_0135 => "A required DLL was not found",
+        0xC000_0139 => "Entry point not found",
+        0xC000_0142 => "DLL initialization failed",
+        0xC000_0374 => "Heap corruption",
+        0xC000_0409 => "Stack buffer overrun",
+        _ => return None,
+    })
 }
 
 impl From<std::process::ExitStatus> for ExitStatus {
@@ -196,7 +1088,7 @@ impl From<std::process::ExitStatus> for ExitStatus {
 
             if let Some(signal) = status.signal() {
                 let signame = unsafe { libc::strsignal(signal) };
-                let signal = if signame.is_null() {
+                let signame = if signame.is_null() {
                     format!("Signal {}", signal)
                 } else {
                     let signame = unsafe { std::ffi::CStr::from_ptr(signame) };
@@ -205,7 +1097,9 @@ impl From<std::process::ExitStatus> for ExitStatus {
 
                 return ExitStatus {
                     code: status.code().map(|c| c as u32).unwrap_or(1),
-                    signal: Some(signal),
+                    signal: Some(signame),
+                    signal_number: Some(signal),
+                    terminated: true,
                 };
             }
         }
@@ -216,7 +1110,7 @@ impl From<std::process::ExitStatus> for ExitStatus {
                 .map(|c| c as u32)
                 .unwrap_or_else(|| if status.success() { 0 } else { 1 });
 
-        ExitStatus { code, signal: None }
+        ExitStatus::with_exit_code(code)
     }
 }
 
@@ -240,6 +1134,33 @@ pub struct PtyPair {
     pub master: Box<dyn MasterPty + Send>,
 }
 
+impl PtyPair {
+    /// Waits for `child` to exit, then closes the pty and returns the
+    /// exit status together with everything that was written to it.
+    /// The output is consumed on a separate thread while waiting, so
+    /// that the child cannot stall on a full pty buffer.  Reading only
+    /// finishes once the pty reports EOF; with conpty that happens after
+    /// the pseudoconsole has flushed its final frame, so trailing output
+    /// such as the last line printed by a short lived command is not lost.
+    /// Any other processes spawned into the pty must also have exited,
+    /// otherwise this will block until they do.
+    pub fn wait_and_drain(self, child: &mut dyn Child) -> anyhow::Result<(ExitStatus, Vec<u8>)> {
+        use std::io::Read;
+
+        let mut reader = self.master.try_clone_reader()?;
+        let drain = std::thread::spawn(move || {
+            let mut output = vec![];
+            reader.read_to_end(&mut output).map(|_| output)
+        });
+        let status = child.wait();
+        drop(self);
+        let output = drain
+            .join()
+            .map_err(|_| anyhow::anyhow!("the pty reader thread panicked"))??;
+        Ok((status?, output))
+    }
+}
+
 /// The `PtySystem` trait allows an application to work with multiple
 /// possible Pty implementations at runtime.  This is important on
 /// Windows systems which have a variety of implementations.
@@ -263,6 +1184,22 @@ impl Child for std::process::Child {
         std::process::Child::wait(self).map(Into::into)
     }
 
+    fn take_stderr(&mut self) -> Option<Box<dyn std::io::Read + Send>> {
+        self.stderr
+            .take()
+            .map(|stderr| Box::new(stderr) as Box<dyn std::io::Read + Send>)
+    }
+
+    #[cfg(unix)]
+    fn is_alive(&self) -> bool {
+        unix_child_is_alive(self.id())
+    }
+
+    #[cfg(windows)]
+    fn is_alive(&self) -> bool {
+        windows_handle_is_alive(std::os::windows::io::AsRawHandle::as_raw_handle(self))
+    }
+
     fn process_id(&self) -> Option<u32> {
         Some(self.id())
     }
@@ -271,6 +1208,180 @@ impl Child for std::process::Child {
     fn as_raw_handle(&self) -> Option<std::os::windows::io::RawHandle> {
         Some(std::os::windows::io::AsRawHandle::as_raw_handle(self))
     }
+
+    #[cfg(unix)]
+    fn current_working_dir(&self) -> Option<std::path::PathBuf> {
+        unix_process_cwd(self.id())
+    }
+
+    #[cfg(windows)]
+    fn current_working_dir(&self) -> Option<std::path::PathBuf> {
+        win::current_working_dir(std::os::windows::io::AsRawHandle::as_raw_handle(self))
+    }
+}
+
+/// Wraps a Child so that it is killed if it is still running
+/// when it is dropped; see `CommandBuilder::kill_on_drop`.
+#[derive(Debug)]
+struct KillOnDrop<C: Child>(Option<C>);
+
+impl<C: Child> KillOnDrop<C> {
+    // The child is only taken by `detach`, which consumes us
+    fn child(&self) -> &C {
+        self.0.as_ref().expect("child was detached")
+    }
+
+    fn child_mut(&mut self) -> &mut C {
+        self.0.as_mut().expect("child was detached")
+    }
+}
+
+impl<C: Child> Drop for KillOnDrop<C> {
+    fn drop(&mut self) {
+        let child = match self.0.as_mut() {
+            Some(child) => child,
+            None => return,
+        };
+        if let Ok(None) = child.try_wait() {
+            if let Err(err) = child.kill() {
+                log::error!("failed to kill child on drop: {:#}", err);
+            } else if let Err(err) = child.wait() {
+                // Reap it, so that it doesn't linger as a zombie
+                log::error!("failed to wait for child on drop: {:#}", err);
+            }
+        }
+    }
+}
+
+impl<C: Child> Child for KillOnDrop<C> {
+    fn try_wait(&mut self) -> IoResult<Option<ExitStatus>> {
+        self.child_mut().try_wait()
+    }
+
+    fn wait(&mut self) -> IoResult<ExitStatus> {
+        self.child_mut().wait()
+    }
+
+    fn wait_timeout(&mut self, timeout: Duration) -> IoResult<Option<ExitStatus>> {
+        self.child_mut().wait_timeout(timeout)
+    }
+
+    fn is_alive(&self) -> bool {
+        self.child().is_alive()
+    }
+
+    fn kill_gracefully(&mut self, grace: Duration) -> IoResult<ExitStatus> {
+        self.child_mut().kill_gracefully(grace)
+    }
+
+    fn send_ctrl_c(&mut self) -> IoResult<()> {
+        self.child_mut().send_ctrl_c()
+    }
+
+    fn send_ctrl_break(&mut self) -> IoResult<()> {
+        self.child_mut().send_ctrl_break()
+    }
+
+    fn resume(&mut self) -> IoResult<()> {
+        self.child_mut().resume()
+    }
+
+    #[cfg(windows)]
+    fn main_thread_handle(&self) -> Option<RawHandle> {
+        self.child().main_thread_handle()
+    }
+
+    fn take_stderr(&mut self) -> Option<Box<dyn std::io::Read + Send>> {
+        self.child_mut().take_stderr()
+    }
+
+    fn release_input(&mut self) {
+        self.child_mut().release_input()
+    }
+
+    fn detach(mut self: Box<Self>) {
+        // Taking the child leaves nothing for our Drop impl to kill
+        if let Some(child) = self.0.take() {
+            Box::new(child).detach();
+        }
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        self.child().process_id()
+    }
+
+    #[cfg(windows)]
+    fn as_raw_handle(&self) -> Option<std::os::windows::io::RawHandle> {
+        self.child().as_raw_handle()
+    }
+
+    fn current_working_dir(&self) -> Option<std::path::PathBuf> {
+        self.child().current_working_dir()
+    }
+}
+
+impl<C: Child> ChildKiller for KillOnDrop<C> {
+    fn kill(&mut self) -> IoResult<()> {
+        self.child_mut().kill()
+    }
+
+    fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
+        self.child().clone_killer()
+    }
+
+    fn send_signal(&mut self, signal: Signal) -> IoResult<()> {
+        self.child_mut().send_signal(signal)
+    }
+}
+
+/// The initial input of a command, see `CommandBuilder::initial_input`,
+/// together with a handle to the input side of the pty.  The handle is
+/// obtained before the command is spawned so that failing to get it
+/// doesn't leave behind a child that we can't return to the caller.
+pub(crate) struct InitialInput {
+    input: Vec<u8>,
+    writer: filedescriptor::FileDescriptor,
+}
+
+impl InitialInput {
+    pub(crate) fn new(input: Vec<u8>, writer: filedescriptor::FileDescriptor) -> Self {
+        Self { input, writer }
+    }
+
+    /// Writes the input on a separate thread, so that spawning doesn't
+    /// block until the child has read input that doesn't fit into the
+    /// buffer of the pty.  If the thread can't be started, `child`,
+    /// which has already been spawned, is killed and reaped rather than
+    /// being orphaned.
+    pub(crate) fn write(self, child: &mut dyn Child) -> Result<(), Error> {
+        let Self { input, mut writer } = self;
+        let res = std::thread::Builder::new()
+            .name("pty initial input".to_string())
+            .spawn(move || {
+                if let Err(err) = writer.write_all(&input) {
+                    log::error!("failed to write the initial input: {}", err);
+                }
+            });
+        if let Err(err) = res {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(Error::new(err).context("failed to write the initial input"));
+        }
+        Ok(())
+    }
+}
+
+/// Box up a newly spawned child, applying the drop policy
+/// that was configured on the CommandBuilder
+pub(crate) fn box_child<C: Child + Send + Sync + 'static>(
+    child: C,
+    kill_on_drop: bool,
+) -> Box<dyn Child + Send + Sync> {
+    if kill_on_drop {
+        Box::new(KillOnDrop(Some(child)))
+    } else {
+        Box::new(child)
+    }
 }
 
 #[derive(Debug)]
@@ -298,7 +1409,12 @@ impl ChildKiller for ProcessSignaller {
     fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
         Box::new(Self {
             pid: self.pid,
-            handle: self.handle.as_ref().and_then(|h| h.try_clone().ok()),
+            handle: self.handle.as_ref().and_then(|h| {
+                h.try_clone()
+                    .map_err(PtyError::handle_clone("child process", "clone a killer"))
+                    .map_err(|err| log::error!("{:#}", Error::from(err)))
+                    .ok()
+            }),
         })
     }
 }
@@ -318,6 +1434,13 @@ impl ChildKiller for ProcessSignaller {
     fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
         Box::new(Self { pid: self.pid })
     }
+
+    fn send_signal(&mut self, signal: Signal) -> IoResult<()> {
+        match self.pid {
+            Some(pid) => send_unix_signal(pid, signal),
+            None => Ok(()),
+        }
+    }
 }
 
 impl ChildKiller for std::process::Child {
@@ -378,13 +1501,206 @@ impl ChildKiller for std::process::Child {
             pid: self.process_id(),
         })
     }
+
+    #[cfg(unix)]
+    fn send_signal(&mut self, signal: Signal) -> IoResult<()> {
+        send_unix_signal(self.id(), signal)
+    }
 }
 
+/// Returns the pty implementation that is most appropriate for the
+/// running system.  On Windows, when the `winpty` feature is enabled
+/// and conpty isn't available, the winpty implementation is selected
+/// instead.
 pub fn native_pty_system() -> Box<dyn PtySystem> {
+    #[cfg(all(windows, feature = "winpty"))]
+    {
+        if !win::conpty::conpty_is_available() {
+            if win::winpty::winpty_is_available() {
+                return Box::new(win::winpty::WinPtySystem::default());
+            }
+            log::error!("neither conpty nor winpty are available on this system");
+        }
+    }
     Box::new(NativePtySystem::default())
 }
 
+/// A command that was spawned into a new pty by `spawn_pty_command`,
+/// along with the handles needed to interact with it.
+pub struct SpawnedCommand {
+    pub child: Box<dyn Child + Send + Sync>,
+    /// Output from the child
+    pub reader: Box<dyn std::io::Read + Send>,
+    /// Input for the child
+    pub writer: Box<dyn std::io::Write + Send>,
+    /// Used to resize the pty.  As noted in `MasterPty::try_clone_reader`,
+    /// the reader only sees EOF once this has been dropped.
+    pub master: Box<dyn MasterPty + Send>,
+}
+
+/// Opens a pty of the specified size using `native_pty_system`,
+/// spawns `cmd` into it and returns the child along with the reader
+/// and writer for the pty, which can then be moved to separate threads.
+/// The slave is released once the command has been spawned, so the
+/// reader sees EOF once the child has exited and the master has been
+/// dropped.
+pub fn spawn_pty_command(cmd: CommandBuilder, size: PtySize) -> anyhow::Result<SpawnedCommand> {
+    let pair = native_pty_system().openpty(size)?;
+    let child = pair.slave.spawn_command_once(cmd)?;
+    let reader = pair.master.try_clone_reader()?;
+    let writer = pair.master.try_clone_writer()?;
+    Ok(SpawnedCommand {
+        child,
+        reader,
+        writer,
+        master: pair.master,
+    })
+}
+
+/// Runs `cmd` to completion in a new pty of the specified size, opened
+/// using `native_pty_system`, and returns its exit status along with
+/// everything that it wrote to the terminal.  This is the pty analog of
+/// `std::process::Command::output`; stdout and stderr are combined, as
+/// they are on a terminal, unless `cmd` redirects stderr elsewhere.
+/// The output is consumed on a helper thread while waiting for the
+/// child, as described in `PtyPair::wait_and_drain`, so a child that
+/// produces lots of output cannot stall on a full pty buffer.
+/// Nothing is written to the input of the pty, so a command that
+/// waits for input never finishes; redirect its stdin via
+/// `CommandBuilder::stdin` if necessary.  Likewise, this returns only
+/// once any background processes that the command left attached to
+/// the pty have exited.
+pub fn run_captured(cmd: CommandBuilder, size: PtySize) -> anyhow::Result<(ExitStatus, Vec<u8>)> {
+    let pair = native_pty_system().openpty(size)?;
+    let mut child = pair.slave.spawn_command(cmd)?;
+    pair.wait_and_drain(&mut *child)
+}
+
 #[cfg(unix)]
 pub type NativePtySystem = unix::UnixPtySystem;
 #[cfg(windows)]
 pub type NativePtySystem = win::conpty::ConPtySystem;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describe_exit_status() {
+        assert_eq!(ExitStatus::with_exit_code(0).describe(), "Success");
+        assert_eq!(
+            ExitStatus::with_exit_code(2).describe(),
+            "Exited with code 2"
+        );
+        assert_eq!(
+            ExitStatus::with_exit_code(0xC0000005).describe(),
+            "Access violation (0xC0000005)"
+        );
+        assert_eq!(
+            ExitStatus::with_exit_code(0xC000013A).describe(),
+            "Interrupted by Ctrl-C (0xC000013A)"
+        );
+        assert_eq!(
+            ExitStatus::with_exit_code(0xE0434352).describe(),
+            "Exited with code 0xE0434352"
+        );
+        assert_eq!(
+            ExitStatus::with_signal("Segmentation fault").describe(),
+            "Segmentation fault"
+        );
+    }
+
+    #[test]
+    fn exit_status_code() {
+        let status = ExitStatus::with_exit_code(0);
+        assert!(status.success());
+        assert_eq!(status.code(), Some(0));
+
+        let status = ExitStatus::with_exit_code(3);
+        assert!(!status.success());
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(status.exit_code(), 3);
+
+        let status = ExitStatus::with_signal("Killed");
+        assert!(!status.success());
+        assert_eq!(status.code(), None);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod unix_tests {
+    use super::*;
+
+    #[test]
+    fn run_captured_output() {
+        let mut cmd = CommandBuilder::new("sh");
+        cmd.args(&["-c", "echo hello; echo oops >&2; exit 3"]);
+        let (status, output) = run_captured(cmd, PtySize::default()).unwrap();
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(String::from_utf8_lossy(&output), "hello\r\noops\r\n");
+    }
+
+    #[test]
+    fn run_captured_large_output() {
+        let mut cmd = CommandBuilder::new("sh");
+        cmd.args(&[
+            "-c",
+            "i=0; while [ $i -lt 5000 ]; do echo line $i; i=$((i+1)); done",
+        ]);
+        let (status, output) = run_captured(cmd, PtySize::default()).unwrap();
+        assert!(status.success());
+        let output = String::from_utf8_lossy(&output);
+        assert!(output.ends_with("line 4999\r\n"), "output was {:?}", output);
+    }
+
+    #[test]
+    fn spawn_pty_command_split() {
+        use std::io::Read;
+
+        let cmd = CommandBuilder::new("cat");
+        let SpawnedCommand {
+            mut child,
+            mut reader,
+            mut writer,
+            master,
+        } = spawn_pty_command(cmd, PtySize::default()).unwrap();
+        writer.write_all(b"hello\n\x04").unwrap();
+        assert!(child.wait().unwrap().success());
+        drop(master);
+        drop(writer);
+
+        let mut output = String::new();
+        reader.read_to_string(&mut output).unwrap();
+        // The input is echoed by the pty and then printed by cat
+        assert!(
+            output.starts_with("hello\r\nhello\r\n"),
+            "output was {:?}",
+            output
+        );
+    }
+
+    #[test]
+    fn pipe_error_classification() {
+        let err = filedescriptor::Error::Pipe(std::io::Error::from_raw_os_error(libc::EMFILE));
+        assert!(matches!(
+            PtyError::pipe(err),
+            PtyError::ResourceExhausted(_)
+        ));
+        let err = filedescriptor::Error::Pipe(std::io::Error::from_raw_os_error(libc::EFAULT));
+        assert!(matches!(PtyError::pipe(err), PtyError::Pipe(_)));
+    }
+
+    #[test]
+    fn is_alive_does_not_reap() {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Child::is_alive(&child) {
+            assert!(Instant::now() < deadline, "child didn't exit");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // The exit status must still be available
+        let status = Child::try_wait(&mut child).unwrap().unwrap();
+        assert!(status.success());
+        assert!(!Child::is_alive(&child));
+    }
+}
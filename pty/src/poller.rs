@@ -0,0 +1,197 @@
+//! Waits for any of a number of ptys to have output available, so that
+//! a multiplexer doesn't need to dedicate a thread to each pty.
+use anyhow::Error;
+use filedescriptor::{AsRawFileDescriptor, FileDescriptor};
+use std::time::Duration;
+#[cfg(windows)]
+use std::time::Instant;
+
+/// A handle to the output side of a pty that can be waited upon for
+/// readability, obtained via `MasterPty::readable_token`.
+/// The token holds its own duplicate of the underlying descriptor, so
+/// it remains valid if the `MasterPty` it came from is dropped; it
+/// then reports EOF as readable.
+#[derive(Debug)]
+pub struct ReadableToken {
+    fd: FileDescriptor,
+}
+
+impl ReadableToken {
+    pub(crate) fn new(fd: FileDescriptor) -> Self {
+        Self { fd }
+    }
+}
+
+#[cfg(unix)]
+impl std::os::unix::io::AsRawFd for ReadableToken {
+    /// The descriptor can be registered with an external `poll`,
+    /// `epoll` or `kqueue` based event loop
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.fd.as_raw_file_descriptor()
+    }
+}
+
+#[cfg(windows)]
+impl std::os::windows::io::AsRawHandle for ReadableToken {
+    /// Note that this is an anonymous pipe handle; those are not
+    /// waitable objects, so passing it to `WaitForMultipleObjects`
+    /// doesn't indicate readability
+    fn as_raw_handle(&self) -> std::os::windows::io::RawHandle {
+        self.fd.as_raw_file_descriptor()
+    }
+}
+
+/// Waits for output to become available on any of a set of registered
+/// ptys.  Each pty is registered along with a caller supplied key,
+/// which is what `poll` returns to identify the ptys that are ready.
+///
+/// ```no_run
+/// use portable_pty::{native_pty_system, PtyPoller, PtySize};
+/// # fn main() -> anyhow::Result<()> {
+/// let pty_system = native_pty_system();
+/// let pair = pty_system.openpty(PtySize::default())?;
+/// let mut poller = PtyPoller::new();
+/// poller.register(1, pair.master.readable_token()?);
+/// let mut buf = [0u8; 4096];
+/// for key in poller.poll(None)? {
+///     assert_eq!(key, 1);
+///     let len = pair.master.read_available(&mut buf)?;
+///     // ...
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// A pty is reported as ready both when it has output pending and once
+/// it has reached EOF, so a ready pty that yields no output should be
+/// unregistered.
+///
+/// On unix this uses `poll(2)`.  The output pipes used on Windows are
+/// anonymous pipes, which can neither be waited upon nor registered
+/// with `WSAPoll`, so there the pipes are checked with `PeekNamedPipe`,
+/// sleeping briefly in between checks until one is ready or the timeout
+/// expires.
+#[derive(Debug, Default)]
+pub struct PtyPoller {
+    entries: Vec<(usize, ReadableToken)>,
+}
+
+impl PtyPoller {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `token` under `key`, replacing any token that was
+    /// previously registered with the same key
+    pub fn register(&mut self, key: usize, token: ReadableToken) {
+        self.unregister(key);
+        self.entries.push((key, token));
+    }
+
+    /// Removes the token registered under `key`, returning it
+    pub fn unregister(&mut self, key: usize) -> Option<ReadableToken> {
+        let idx = self.entries.iter().position(|(k, _)| *k == key)?;
+        Some(self.entries.remove(idx).1)
+    }
+
+    /// Returns true if there are no registered ptys
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Blocks until at least one of the registered ptys is readable, or
+    /// until `timeout` has elapsed, and returns the keys of the ready
+    /// ptys.  The result is empty if the timeout expired.
+    /// A timeout of None waits indefinitely.
+    #[cfg(unix)]
+    pub fn poll(&mut self, timeout: Option<Duration>) -> Result<Vec<usize>, Error> {
+        use filedescriptor::{poll, pollfd, POLLIN};
+
+        let mut pfds: Vec<pollfd> = self
+            .entries
+            .iter()
+            .map(|(_, token)| pollfd {
+                fd: token.fd.as_raw_file_descriptor(),
+                events: POLLIN,
+                revents: 0,
+            })
+            .collect();
+        if poll(&mut pfds, timeout)? == 0 {
+            return Ok(vec![]);
+        }
+        Ok(self
+            .entries
+            .iter()
+            .zip(pfds.iter())
+            // POLLHUP and POLLERR are reported as readable, as
+            // reading will then produce EOF or the error
+            .filter(|(_, pfd)| pfd.revents != 0)
+            .map(|((key, _), _)| *key)
+            .collect())
+    }
+
+    /// Blocks until at least one of the registered ptys is readable, or
+    /// until `timeout` has elapsed, and returns the keys of the ready
+    /// ptys.  The result is empty if the timeout expired.
+    /// A timeout of None waits indefinitely.
+    #[cfg(windows)]
+    pub fn poll(&mut self, timeout: Option<Duration>) -> Result<Vec<usize>, Error> {
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        loop {
+            let mut ready = vec![];
+            for (key, token) in &self.entries {
+                match crate::win::pending_bytes(&token.fd)? {
+                    // Broken pipe, which is EOF
+                    None => ready.push(*key),
+                    Some(0) => {}
+                    Some(_) => ready.push(*key),
+                }
+            }
+            if !ready.is_empty() {
+                return Ok(ready);
+            }
+            let mut wait = Duration::from_millis(5);
+            if let Some(deadline) = deadline {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Ok(vec![]);
+                }
+                wait = wait.min(deadline - now);
+            }
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{native_pty_system, PtySize};
+
+    #[test]
+    fn reports_ready_ptys() {
+        let pty_system = native_pty_system();
+        let a = pty_system.openpty(PtySize::default()).unwrap();
+        let b = pty_system.openpty(PtySize::default()).unwrap();
+
+        let mut poller = PtyPoller::new();
+        poller.register(1, a.master.readable_token().unwrap());
+        poller.register(2, b.master.readable_token().unwrap());
+        assert!(poller
+            .poll(Some(Duration::from_millis(0)))
+            .unwrap()
+            .is_empty());
+
+        // The line discipline echoes input back to the master
+        b.master.write_all_pty(b"hello\n").unwrap();
+        assert_eq!(poller.poll(Some(Duration::from_secs(5))).unwrap(), vec![2]);
+
+        assert!(poller.unregister(2).is_some());
+        assert!(poller.unregister(2).is_none());
+        assert!(poller
+            .poll(Some(Duration::from_millis(0)))
+            .unwrap()
+            .is_empty());
+        assert!(!poller.is_empty());
+    }
+}
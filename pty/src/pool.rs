@@ -0,0 +1,166 @@
+//! Reading pty output into reusable buffers, so that a terminal that
+//! hands each chunk of output to another thread, such as a parser or
+//! renderer, doesn't need to allocate a new buffer for every chunk.
+use std::io::{ErrorKind, Read, Result as IoResult};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+/// A pool of equally sized buffers.  Cloning a `BufferPool` yields
+/// another handle to the same pool.
+#[derive(Clone, Debug)]
+pub struct BufferPool {
+    free: Arc<Mutex<Vec<Vec<u8>>>>,
+    buffer_size: usize,
+    max_free: usize,
+}
+
+impl BufferPool {
+    /// Creates a pool of buffers of `buffer_size` bytes.  Up to
+    /// `max_free` buffers that are no longer in use are retained for
+    /// reuse; any beyond that are freed.
+    /// Panics if `buffer_size` is 0, as reading into an empty buffer
+    /// would be indistinguishable from EOF.
+    pub fn new(buffer_size: usize, max_free: usize) -> Self {
+        assert!(buffer_size > 0, "BufferPool buffer_size must be non-zero");
+        Self {
+            free: Arc::new(Mutex::new(Vec::with_capacity(max_free))),
+            buffer_size,
+            max_free,
+        }
+    }
+
+    /// Returns the size of the buffers in the pool
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Takes a buffer from the pool, allocating one if none is free
+    fn take(&self) -> Vec<u8> {
+        match self.free.lock().unwrap().pop() {
+            Some(buf) => buf,
+            None => vec![0u8; self.buffer_size],
+        }
+    }
+
+    fn give_back(&self, buf: Vec<u8>) {
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.max_free {
+            free.push(buf);
+        }
+    }
+}
+
+/// A chunk of output held in a buffer that came from a `BufferPool`.
+/// It dereferences to the bytes that were read, and the buffer is
+/// returned to the pool when the chunk is dropped, which may happen on
+/// a different thread from the one that read it.
+#[derive(Debug)]
+pub struct PooledBuffer {
+    buf: Vec<u8>,
+    len: usize,
+    pool: BufferPool,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.give_back(std::mem::take(&mut self.buf));
+    }
+}
+
+/// Reads from a pty into buffers taken from a `BufferPool`; obtain one
+/// via `MasterPty::pooled_reader`.
+/// Once buffers are being returned to the pool as fast as they are
+/// filled, reading doesn't allocate.  The buffers are only zeroed when
+/// they are first allocated.
+pub struct PooledReader<R> {
+    reader: R,
+    pool: BufferPool,
+}
+
+impl<R: Read> PooledReader<R> {
+    pub fn new(reader: R, pool: BufferPool) -> Self {
+        Self { reader, pool }
+    }
+
+    /// Blocks until output is available and returns it, or returns
+    /// None once the reader reaches EOF
+    pub fn read_chunk(&mut self) -> IoResult<Option<PooledBuffer>> {
+        let mut buf = self.pool.take();
+        loop {
+            match self.reader.read(&mut buf) {
+                Ok(0) => {
+                    self.pool.give_back(buf);
+                    return Ok(None);
+                }
+                Ok(len) => {
+                    return Ok(Some(PooledBuffer {
+                        buf,
+                        len,
+                        pool: self.pool.clone(),
+                    }))
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => {
+                    self.pool.give_back(buf);
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// Returns the wrapped reader
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_buffers() {
+        let data: Vec<u8> = (0..100u8).collect();
+        let pool = BufferPool::new(16, 2);
+        let mut reader = PooledReader::new(&data[..], pool.clone());
+
+        let mut output = vec![];
+        let mut addresses = vec![];
+        while let Some(chunk) = reader.read_chunk().unwrap() {
+            output.extend_from_slice(&chunk);
+            addresses.push(chunk.as_ptr());
+        }
+        assert_eq!(output, data);
+        // Each chunk was dropped before the next read, so the same
+        // buffer was used every time
+        assert!(addresses.iter().all(|addr| *addr == addresses[0]));
+        assert_eq!(pool.free.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn limits_free_buffers() {
+        let data = vec![0u8; 64];
+        let pool = BufferPool::new(16, 2);
+        let mut reader = PooledReader::new(&data[..], pool.clone());
+        let mut chunks = vec![];
+        while let Some(chunk) = reader.read_chunk().unwrap() {
+            chunks.push(chunk);
+        }
+        assert_eq!(chunks.len(), 4);
+        drop(chunks);
+        assert_eq!(pool.free.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_empty_buffers() {
+        BufferPool::new(0, 2);
+    }
+}
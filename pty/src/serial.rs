@@ -227,6 +227,12 @@ impl MasterPty for Master {
         // N/A: there is no local process
         None
     }
+
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        use std::os::unix::io::AsRawFd;
+        Some(self.port.lock().unwrap().as_raw_fd())
+    }
 }
 
 struct Reader {
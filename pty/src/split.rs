@@ -0,0 +1,132 @@
+//! Splitting a `MasterPty` into separately owned halves, so that output
+//! can be read on one thread while input is written on another.
+use crate::{MasterPty, PtyError};
+use filedescriptor::FileDescriptor;
+use std::io::{Read, Result as IoResult, Write};
+
+enum Reader {
+    Fd(FileDescriptor),
+    Boxed(Box<dyn Read + Send>),
+}
+
+enum Writer {
+    Fd(FileDescriptor),
+    Boxed(Box<dyn Write + Send>),
+}
+
+/// The reading half of a `MasterPty`, obtained via `split`.
+/// For the native ptys it owns the output handle directly, so reading
+/// doesn't go through dynamic dispatch.
+/// Reading returns EOF once the slave side has been closed and the
+/// remaining output has been read.
+/// With conpty the output pipe stays open for as long as the
+/// pseudoconsole does, which is owned by the master, so on Windows the
+/// reader only sees EOF once the `PtyWriter`, which holds the master,
+/// has been dropped as well as the slave.
+pub struct PtyReader {
+    reader: Reader,
+}
+
+/// The writing half of a `MasterPty`, obtained via `split`.
+/// For the native ptys it owns the input handle directly, so writing
+/// doesn't go through dynamic dispatch.
+/// It also owns the `MasterPty` itself, which remains available via
+/// `master` for resizing and other control operations, and which is
+/// dropped along with the writer.
+pub struct PtyWriter {
+    writer: Writer,
+    master: Box<dyn MasterPty + Send>,
+}
+
+/// Splits `master` into a reader and a writer.  Ptys that don't provide
+/// their handles via `MasterPty::handle_set` fall back to the boxed
+/// reader and writer; other failures, such as running out of handles,
+/// are returned.
+pub(crate) fn split(master: Box<dyn MasterPty + Send>) -> anyhow::Result<(PtyReader, PtyWriter)> {
+    let (reader, writer) = match master.handle_set() {
+        Ok(handles) => (Reader::Fd(handles.read), Writer::Fd(handles.write)),
+        Err(err)
+            if matches!(
+                err.downcast_ref::<PtyError>(),
+                Some(PtyError::HandlesUnavailable)
+            ) =>
+        {
+            (
+                Reader::Boxed(master.try_clone_reader()?),
+                Writer::Boxed(master.try_clone_writer()?),
+            )
+        }
+        Err(err) => return Err(err),
+    };
+    Ok((PtyReader { reader }, PtyWriter { writer, master }))
+}
+
+impl Read for PtyReader {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        match &mut self.reader {
+            Reader::Fd(fd) => match fd.read(buf) {
+                // The master reports EIO once the slave has been
+                // closed, which we treat as EOF, as the reader
+                // returned by `try_clone_reader` does
+                #[cfg(unix)]
+                Err(err) if err.raw_os_error() == Some(libc::EIO) => Ok(0),
+                res => res,
+            },
+            Reader::Boxed(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl PtyWriter {
+    /// Returns the `MasterPty` that this was split from
+    pub fn master(&self) -> &(dyn MasterPty + Send) {
+        &*self.master
+    }
+}
+
+impl Write for PtyWriter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        match &mut self.writer {
+            Writer::Fd(fd) => fd.write(buf),
+            Writer::Boxed(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        match &mut self.writer {
+            Writer::Fd(fd) => fd.flush(),
+            Writer::Boxed(writer) => writer.flush(),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::{native_pty_system, CommandBuilder, PtySize};
+    use std::io::{Read, Write};
+
+    #[test]
+    fn read_and_write_halves() {
+        let pair = native_pty_system().openpty(PtySize::default()).unwrap();
+        let mut cmd = CommandBuilder::new("sh");
+        cmd.args(&["-c", "read line; echo \"got $line\""]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        drop(pair.slave);
+
+        let (mut reader, mut writer) = pair.master.split().unwrap();
+        let size = PtySize {
+            rows: 30,
+            cols: 100,
+            ..PtySize::default()
+        };
+        writer.master().resize(size).unwrap();
+        assert_eq!(writer.master().get_size().unwrap(), size);
+        writer.write_all(b"hello\n").unwrap();
+
+        // The reader sees EOF once the child exits
+        let mut output = String::new();
+        reader.read_to_string(&mut output).unwrap();
+        assert!(output.contains("got hello"), "output was {:?}", output);
+        assert!(child.wait().unwrap().success());
+    }
+}
@@ -214,6 +214,9 @@ impl SlavePty for SshSlave {
                 let command = cmd.as_unix_command_line()?;
                 channel.exec(&command)?;
             }
+            if !cmd.initial_input.is_empty() {
+                channel.write_all(&cmd.initial_input)?;
+            }
 
             let child: Box<dyn Child + Send + Sync> = Box::new(SshChild {
                 pty: self.pty.clone(),
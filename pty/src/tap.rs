@@ -0,0 +1,102 @@
+//! Retains the most recent output read from a pty so that it can be
+//! attached to diagnostics, such as a bug report about a hung program.
+use std::collections::VecDeque;
+use std::io::{Read, Result as IoResult};
+use std::sync::{Arc, Mutex};
+
+/// A fixed size ring buffer holding the most recent output that was
+/// read through the reader returned alongside it by `MasterPty::with_tap`.
+/// Cloning an `OutputTap` yields another handle to the same buffer.
+#[derive(Clone, Debug)]
+pub struct OutputTap {
+    inner: Arc<Mutex<VecDeque<u8>>>,
+    capacity: usize,
+}
+
+impl OutputTap {
+    fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Returns a copy of the retained output, oldest byte first.
+    /// At most `capacity` bytes are returned.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let buf = self.inner.lock().unwrap();
+        buf.iter().copied().collect()
+    }
+
+    /// Returns the maximum number of bytes that are retained
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn record(&self, data: &[u8]) {
+        // Only the tail of a large read can fit
+        let data = &data[data.len().saturating_sub(self.capacity)..];
+        let mut buf = self.inner.lock().unwrap();
+        let excess = (buf.len() + data.len()).saturating_sub(self.capacity);
+        buf.drain(..excess);
+        buf.extend(data);
+    }
+}
+
+/// Wraps a reader, copying everything that is read into an `OutputTap`
+pub(crate) struct TapReader<R> {
+    reader: R,
+    tap: OutputTap,
+}
+
+impl<R: Read> TapReader<R> {
+    pub(crate) fn new(reader: R, capacity: usize) -> (Self, OutputTap) {
+        let tap = OutputTap::new(capacity);
+        (
+            Self {
+                reader,
+                tap: tap.clone(),
+            },
+            tap,
+        )
+    }
+}
+
+impl<R: Read> Read for TapReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let len = self.reader.read(buf)?;
+        self.tap.record(&buf[..len]);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retains_most_recent_output() {
+        let data: Vec<u8> = (0..100u8).collect();
+        let (mut reader, tap) = TapReader::new(&data[..], 10);
+        let mut buf = [0u8; 7];
+        while reader.read(&mut buf).unwrap() > 0 {}
+        assert_eq!(tap.snapshot(), (90..100u8).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn large_read_keeps_tail() {
+        let data: Vec<u8> = (0..100u8).collect();
+        let (mut reader, tap) = TapReader::new(&data[..], 10);
+        let mut output = vec![];
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, data);
+        assert_eq!(tap.snapshot(), (90..100u8).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn short_output() {
+        let (mut reader, tap) = TapReader::new(&b"hello"[..], 10);
+        std::io::copy(&mut reader, &mut std::io::sink()).unwrap();
+        assert_eq!(tap.snapshot(), b"hello");
+    }
+}
@@ -1,27 +1,28 @@
 //! Working with pseudo-terminals
 
-use crate::{Child, CommandBuilder, MasterPty, PtyPair, PtySize, PtySystem, SlavePty};
+use crate::{
+    box_child, Child, CommandBuilder, InitialInput, MasterPty, PtyError, PtyHandleSet, PtyPair,
+    PtySize, PtySystem, ReadableToken, SlavePty, StdioMode,
+};
 use anyhow::{bail, Error};
 use filedescriptor::FileDescriptor;
 use libc::{self, winsize};
+use std::ffi::CStr;
 use std::io::{Read, Write};
-use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 use std::os::unix::process::CommandExt;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex, Weak};
 use std::{io, mem, ptr};
 
 #[derive(Default)]
 pub struct UnixPtySystem {}
 
-fn openpty(size: PtySize) -> anyhow::Result<(UnixMasterPty, UnixSlavePty)> {
+fn openpty(pty_size: PtySize) -> anyhow::Result<(UnixMasterPty, UnixSlavePty)> {
     let mut master: RawFd = -1;
     let mut slave: RawFd = -1;
 
-    let mut size = winsize {
-        ws_row: size.rows,
-        ws_col: size.cols,
-        ws_xpixel: size.pixel_width,
-        ws_ypixel: size.pixel_height,
-    };
+    let mut size: winsize = pty_size.into();
 
     let result = unsafe {
         // BSDish systems may require mut pointers to some args
@@ -36,26 +37,47 @@ fn openpty(size: PtySize) -> anyhow::Result<(UnixMasterPty, UnixSlavePty)> {
     };
 
     if result != 0 {
-        bail!("failed to openpty: {:?}", io::Error::last_os_error());
+        return Err(PtyError::OpenPty(io::Error::last_os_error()).into());
     }
 
-    let master = UnixMasterPty {
-        fd: PtyFd(unsafe { FileDescriptor::from_raw_fd(master) }),
-    };
-    let slave = UnixSlavePty {
-        fd: PtyFd(unsafe { FileDescriptor::from_raw_fd(slave) }),
-    };
+    let master = PtyFd(unsafe { FileDescriptor::from_raw_fd(master) });
+    let slave = PtyFd(unsafe { FileDescriptor::from_raw_fd(slave) });
 
     // Ensure that these descriptors will get closed when we execute
-    // the child process.  This is done after constructing the Pty
-    // instances so that we ensure that the Ptys get drop()'d if
+    // the child process.  This is done after constructing the PtyFd
+    // instances so that we ensure that the descriptors get drop()'d if
     // the cloexec() functions fail (unlikely!).
-    cloexec(master.fd.as_raw_fd())?;
-    cloexec(slave.fd.as_raw_fd())?;
+    cloexec(master.as_raw_fd())?;
+    cloexec(slave.as_raw_fd())?;
+
+    let input = Arc::new(master.try_clone().map_err(PtyError::handle_clone(
+        "pty master",
+        "retain it for the initial input",
+    ))?);
+    let slave = UnixSlavePty {
+        fd: slave,
+        master_input: Arc::downgrade(&input),
+    };
+    let master = UnixMasterPty {
+        fd: master,
+        size: Arc::new(Mutex::new(pty_size)),
+        input,
+    };
 
     Ok((master, slave))
 }
 
+impl From<PtySize> for winsize {
+    fn from(size: PtySize) -> winsize {
+        winsize {
+            ws_row: size.rows,
+            ws_col: size.cols,
+            ws_xpixel: size.pixel_width,
+            ws_ypixel: size.pixel_height,
+        }
+    }
+}
+
 impl PtySystem for UnixPtySystem {
     fn openpty(&self, size: PtySize) -> anyhow::Result<PtyPair> {
         let (master, slave) = openpty(size)?;
@@ -112,6 +134,11 @@ impl Read for PtyFd {
 /// to provide the list of open fds.  Any errors in enumerating or closing
 /// the fds are silently ignored.
 pub fn close_random_fds() {
+    close_random_fds_except(&[])
+}
+
+/// Like `close_random_fds`, but leaves the descriptors in `keep` open
+fn close_random_fds_except(keep: &[RawFd]) {
     // FreeBSD, macOS and presumably other BSDish systems have /dev/fd as
     // a directory listing the current fd numbers for the process.
     //
@@ -125,7 +152,7 @@ pub fn close_random_fds() {
                 .and_then(|s| s.into_string().ok())
                 .and_then(|n| n.parse::<libc::c_int>().ok())
             {
-                if num > 2 {
+                if num > 2 && !keep.contains(&num) {
                     fds.push(num);
                 }
             }
@@ -140,12 +167,7 @@ pub fn close_random_fds() {
 
 impl PtyFd {
     fn resize(&self, size: PtySize) -> Result<(), Error> {
-        let ws_size = winsize {
-            ws_row: size.rows,
-            ws_col: size.cols,
-            ws_xpixel: size.pixel_width,
-            ws_ypixel: size.pixel_height,
-        };
+        let ws_size: winsize = size.into();
 
         if unsafe {
             libc::ioctl(
@@ -155,10 +177,11 @@ impl PtyFd {
             )
         } != 0
         {
-            bail!(
-                "failed to ioctl(TIOCSWINSZ): {:?}",
-                io::Error::last_os_error()
-            );
+            return Err(PtyError::Resize {
+                size,
+                source: io::Error::last_os_error(),
+            }
+            .into());
         }
 
         Ok(())
@@ -188,84 +211,179 @@ impl PtyFd {
     }
 
     fn spawn_command(&self, builder: CommandBuilder) -> anyhow::Result<std::process::Child> {
-        let configured_umask = builder.umask;
-
-        let mut cmd = builder.as_command()?;
-
-        unsafe {
-            cmd.stdin(self.as_stdio()?)
-                .stdout(self.as_stdio()?)
-                .stderr(self.as_stdio()?)
-                .pre_exec(move || {
-                    // Clean up a few things before we exec the program
-                    // Clear out any potentially problematic signal
-                    // dispositions that we might have inherited
-                    for signo in &[
-                        libc::SIGCHLD,
-                        libc::SIGHUP,
-                        libc::SIGINT,
-                        libc::SIGQUIT,
-                        libc::SIGTERM,
-                        libc::SIGALRM,
-                    ] {
-                        libc::signal(*signo, libc::SIG_DFL);
-                    }
+        check_stdio(&builder)?;
+        let stdin = self.stdio_for(builder.stdin)?;
+        let stdout = self.stdio_for(builder.stdout)?;
+        let stderr = self.stdio_for(builder.stderr)?;
+        spawn_with_stdio(builder, self.as_raw_fd(), stdin, stdout, stderr)
+    }
+
+    /// Returns the Stdio to use for a stream configured with `mode`
+    fn stdio_for(&self, mode: StdioMode) -> anyhow::Result<Stdio> {
+        Ok(match mode {
+            StdioMode::Pty => self.as_stdio()?,
+            StdioMode::Inherit => Stdio::inherit(),
+            StdioMode::Null => Stdio::null(),
+            StdioMode::Pipe => Stdio::piped(),
+        })
+    }
+}
 
-                    // Establish ourselves as a session leader.
-                    if libc::setsid() == -1 {
+/// Only stderr can be connected to a pipe; see `Child::take_stderr`
+fn check_stdio(builder: &CommandBuilder) -> anyhow::Result<()> {
+    if builder.stdin == StdioMode::Pipe || builder.stdout == StdioMode::Pipe {
+        bail!("StdioMode::Pipe is only supported for stderr");
+    }
+    Ok(())
+}
+
+/// Spawns the command with the provided stdio.
+/// `slave` is the slave side of the pty; it is made the controlling
+/// terminal of the child, and must remain open until this returns.
+fn spawn_with_stdio(
+    builder: CommandBuilder,
+    slave: RawFd,
+    stdin: Stdio,
+    stdout: Stdio,
+    stderr: Stdio,
+) -> anyhow::Result<std::process::Child> {
+    let configured_umask = builder.umask;
+    let priority = builder.priority;
+    let stderr_mode = builder.stderr;
+    let controlling_tty = builder.controlling_tty;
+    let inherit_fds = builder.inherit_fds.clone();
+
+    let mut cmd = builder.as_command()?;
+
+    // The Debug impl of Command includes the values of all of the
+    // environment variables, which may be secrets, so describe the
+    // command for errors without them
+    let command = format!(
+        "{:?} with argv {:?} in {:?}",
+        cmd.get_program(),
+        builder.get_argv(),
+        cmd.get_current_dir()
+            .unwrap_or_else(|| std::path::Path::new("."))
+    );
+
+    let (inherit_env, env_set) = builder.env_summary();
+    log::debug!(
+        "spawning program={:?} argv={:?} cwd={:?} inherit_env={} env_set={:?}",
+        cmd.get_program(),
+        builder.get_argv(),
+        cmd.get_current_dir(),
+        inherit_env,
+        env_set
+    );
+
+    unsafe {
+        cmd.stdin(stdin)
+            .stdout(stdout)
+            .stderr(stderr)
+            .pre_exec(move || {
+                // Clean up a few things before we exec the program
+                // Clear out any potentially problematic signal
+                // dispositions that we might have inherited
+                for signo in &[
+                    libc::SIGCHLD,
+                    libc::SIGHUP,
+                    libc::SIGINT,
+                    libc::SIGQUIT,
+                    libc::SIGTERM,
+                    libc::SIGALRM,
+                ] {
+                    libc::signal(*signo, libc::SIG_DFL);
+                }
+
+                // Establish ourselves as a session leader.
+                if libc::setsid() == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                // Clippy wants us to explicitly cast TIOCSCTTY using
+                // type::from(), but the size and potentially signedness
+                // are system dependent, which is why we're using `as _`.
+                // Suppress this lint for this section of code.
+                #[cfg_attr(feature = "cargo-clippy", allow(clippy::cast_lossless))]
+                {
+                    // Set the pty as the controlling terminal.
+                    // Failure to do this means that delivery of
+                    // SIGWINCH won't happen when we resize the
+                    // terminal, among other undesirable effects.
+                    if controlling_tty && libc::ioctl(slave, libc::TIOCSCTTY as _, 0) == -1 {
                         return Err(io::Error::last_os_error());
                     }
+                }
 
-                    // Clippy wants us to explicitly cast TIOCSCTTY using
-                    // type::from(), but the size and potentially signedness
-                    // are system dependent, which is why we're using `as _`.
-                    // Suppress this lint for this section of code.
-                    #[cfg_attr(feature = "cargo-clippy", allow(clippy::cast_lossless))]
+                close_random_fds_except(&inherit_fds);
+
+                // Descriptors are normally opened close-on-exec, so
+                // clear that flag on those that we're passing on
+                for &fd in &inherit_fds {
+                    let flags = libc::fcntl(fd, libc::F_GETFD);
+                    if flags == -1
+                        || libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) == -1
                     {
-                        // Set the pty as the controlling terminal.
-                        // Failure to do this means that delivery of
-                        // SIGWINCH won't happen when we resize the
-                        // terminal, among other undesirable effects.
-                        if libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
-                            return Err(io::Error::last_os_error());
-                        }
+                        return Err(io::Error::last_os_error());
                     }
+                }
 
-                    close_random_fds();
+                if let Some(mask) = configured_umask {
+                    libc::umask(mask);
+                }
 
-                    if let Some(mask) = configured_umask {
-                        libc::umask(mask);
+                if let Some(priority) = priority {
+                    if libc::setpriority(libc::PRIO_PROCESS as _, 0, priority.nice_value()) == -1 {
+                        return Err(io::Error::last_os_error());
                     }
+                }
 
-                    Ok(())
-                })
-        };
+                Ok(())
+            })
+    };
 
-        let mut child = cmd.spawn()?;
+    let mut child = cmd.spawn().map_err(|source| {
+        log::debug!("spawning {:?} failed: {}", cmd.get_program(), source);
+        PtyError::Spawn { command, source }
+    })?;
+    log::debug!("spawned {:?} as pid {}", cmd.get_program(), child.id());
 
-        // Ensure that we close out the slave fds that Child retains;
-        // they are not what we need (we need the master side to reference
-        // them) and won't work in the usual way anyway.
-        // In practice these are None, but it seems best to be move them
-        // out in case the behavior of Command changes in the future.
-        child.stdin.take();
-        child.stdout.take();
+    // Ensure that we close out the slave fds that Child retains;
+    // they are not what we need (we need the master side to reference
+    // them) and won't work in the usual way anyway.
+    // In practice these are None, but it seems best to be move them
+    // out in case the behavior of Command changes in the future.
+    // stderr is the exception: when it is configured as a pipe, we
+    // retain it for Child::take_stderr.
+    child.stdin.take();
+    child.stdout.take();
+    if stderr_mode != StdioMode::Pipe {
         child.stderr.take();
-
-        Ok(child)
     }
+
+    Ok(child)
 }
 
 /// Represents the master end of a pty.
 /// The file descriptor will be closed when the Pty is dropped.
 struct UnixMasterPty {
     fd: PtyFd,
+    /// The size most recently set or observed, which is reported
+    /// if the kernel can't be queried
+    size: Arc<Mutex<PtySize>>,
+    /// A duplicate of `fd` through which the slave writes the initial
+    /// input of the commands that it spawns
+    input: Arc<FileDescriptor>,
 }
 
 /// Represents the slave end of a pty.
 /// The file descriptor will be closed when the Pty is dropped.
 struct UnixSlavePty {
     fd: PtyFd,
+    /// This is weak so that the slave doesn't keep the master open,
+    /// which would prevent the child from seeing a hangup once the
+    /// master has been dropped
+    master_input: Weak<FileDescriptor>,
 }
 
 /// Helper function to set the close-on-exec flag for a raw descriptor
@@ -288,31 +406,136 @@ fn cloexec(fd: RawFd) -> Result<(), Error> {
 }
 
 impl SlavePty for UnixSlavePty {
+    fn name(&self) -> Option<String> {
+        let mut buf = [0 as libc::c_char; 128];
+        let res = unsafe { libc::ttyname_r(self.fd.as_raw_fd(), buf.as_mut_ptr(), buf.len()) };
+        if res != 0 {
+            return None;
+        }
+        let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+        Some(name.to_string_lossy().into_owned())
+    }
+
+    fn with_termios(&self, f: &mut dyn FnMut(&mut libc::termios)) -> Result<(), Error> {
+        let fd = self.fd.as_raw_fd();
+        let mut termios = unsafe { mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+            return Err(PtyError::Termios(io::Error::last_os_error()).into());
+        }
+        f(&mut termios);
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } != 0 {
+            return Err(PtyError::Termios(io::Error::last_os_error()).into());
+        }
+        Ok(())
+    }
+
     fn spawn_command(
         &self,
-        builder: CommandBuilder,
+        mut builder: CommandBuilder,
     ) -> Result<Box<dyn Child + Send + Sync>, Error> {
-        Ok(Box::new(self.fd.spawn_command(builder)?))
+        let kill_on_drop = builder.kill_on_drop;
+        let initial_input = self.initial_input(&mut builder)?;
+        let mut child = box_child(self.fd.spawn_command(builder)?, kill_on_drop);
+        if let Some(initial_input) = initial_input {
+            initial_input.write(&mut *child)?;
+        }
+        Ok(child)
+    }
+
+    fn spawn_command_once(
+        self: Box<Self>,
+        mut builder: CommandBuilder,
+    ) -> Result<Box<dyn Child + Send + Sync>, Error> {
+        if builder.stdin != StdioMode::Pty {
+            return self.spawn_command(builder);
+        }
+        check_stdio(&builder)?;
+        let kill_on_drop = builder.kill_on_drop;
+        let initial_input = self.initial_input(&mut builder)?;
+        let stdout = self.fd.stdio_for(builder.stdout)?;
+        let stderr = self.fd.stdio_for(builder.stderr)?;
+        let slave = self.fd.as_raw_fd();
+        // We're done with the slave, so move its descriptor into
+        // stdin rather than duplicating it a third time
+        let stdin = unsafe { Stdio::from_raw_fd(self.fd.0.into_raw_fd()) };
+        let mut child = box_child(
+            spawn_with_stdio(builder, slave, stdin, stdout, stderr)?,
+            kill_on_drop,
+        );
+        if let Some(initial_input) = initial_input {
+            initial_input.write(&mut *child)?;
+        }
+        Ok(child)
+    }
+}
+
+impl UnixSlavePty {
+    /// Takes the initial input, if any, from `builder`.  This is done
+    /// before spawning so that we don't spawn the command if the master
+    /// has already been dropped and the input can't be delivered.
+    fn initial_input(&self, builder: &mut CommandBuilder) -> Result<Option<InitialInput>, Error> {
+        if builder.initial_input.is_empty() {
+            return Ok(None);
+        }
+        let master = match self.master_input.upgrade() {
+            Some(master) => master,
+            None => bail!("unable to write the initial input because the master has been dropped"),
+        };
+        let writer = master.try_clone().map_err(PtyError::handle_clone(
+            "pty master",
+            "write the initial input",
+        ))?;
+        Ok(Some(InitialInput::new(
+            std::mem::take(&mut builder.initial_input),
+            writer,
+        )))
     }
 }
 
 impl MasterPty for UnixMasterPty {
     fn resize(&self, size: PtySize) -> Result<(), Error> {
-        self.fd.resize(size)
+        self.fd.resize(size)?;
+        *self.size.lock().unwrap() = size;
+        Ok(())
     }
 
+    /// The size is queried from the kernel, because it can also be
+    /// changed via the slave.  If that fails, the last known size is
+    /// returned instead.
     fn get_size(&self) -> Result<PtySize, Error> {
-        self.fd.get_size()
+        let mut cached = self.size.lock().unwrap();
+        match self.fd.get_size() {
+            Ok(size) => {
+                *cached = size;
+                Ok(size)
+            }
+            Err(err) => {
+                log::warn!("{:#}; using the last known size", err);
+                Ok(*cached)
+            }
+        }
     }
 
     fn try_clone_reader(&self) -> Result<Box<dyn Read + Send>, Error> {
-        let fd = PtyFd(self.fd.try_clone()?);
+        let fd = PtyFd(
+            self.fd
+                .try_clone()
+                .map_err(PtyError::handle_clone("pty master", "create a reader"))?,
+        );
         Ok(Box::new(fd))
     }
 
     fn try_clone_writer(&self) -> Result<Box<dyn Write + Send>, Error> {
-        let fd = PtyFd(self.fd.try_clone()?);
-        Ok(Box::new(UnixMasterPty { fd }))
+        let fd = PtyFd(
+            self.fd
+                .try_clone()
+                .map_err(PtyError::handle_clone("pty master", "create a writer"))?,
+        );
+        Ok(Box::new(UnixMasterPty {
+            fd,
+            size: Arc::clone(&self.size),
+            input: Arc::clone(&self.input),
+        }))
     }
 
     fn process_group_leader(&self) -> Option<libc::pid_t> {
@@ -321,6 +544,79 @@ impl MasterPty for UnixMasterPty {
             _ => None,
         }
     }
+
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        Some(AsRawFd::as_raw_fd(self))
+    }
+
+    fn read_output(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fd.read(buf)
+    }
+
+    fn readable_token(&self) -> Result<ReadableToken, Error> {
+        Ok(ReadableToken::new(self.fd.try_clone().map_err(
+            PtyError::handle_clone("pty master", "create a readable token"),
+        )?))
+    }
+
+    fn handle_set(&self) -> Result<PtyHandleSet, Error> {
+        Ok(PtyHandleSet {
+            read: self
+                .fd
+                .try_clone()
+                .map_err(PtyError::handle_clone("pty master", "share it"))?,
+            write: self
+                .fd
+                .try_clone()
+                .map_err(PtyError::handle_clone("pty master", "share it"))?,
+        })
+    }
+
+    /// The termios settings of the slave are read via the master
+    fn send_eof(&self) -> Result<(), Error> {
+        let mut termios = unsafe { mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(self.fd.as_raw_fd(), &mut termios) } != 0 {
+            return Err(PtyError::Termios(io::Error::last_os_error()).into());
+        }
+        if termios.c_lflag & libc::ICANON == 0 {
+            bail!("cannot send EOF because the pty is not in canonical mode");
+        }
+        self.write_all_pty(&[termios.c_cc[libc::VEOF]])
+    }
+
+    /// Polls rather than setting O_NONBLOCK, because the flag would
+    /// also apply to readers that were cloned from this pty
+    fn read_available(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        use filedescriptor::{poll, pollfd, POLLIN};
+
+        let fd = self.fd.as_raw_fd();
+        let mut pfd = [pollfd {
+            fd,
+            events: POLLIN,
+            revents: 0,
+        }];
+        if poll(&mut pfd, Some(std::time::Duration::from_millis(0)))? == 0 {
+            return Ok(0);
+        }
+        let len = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // EIO means that the slave has been closed, see the Read
+                // impl for PtyFd, and EAGAIN can only happen if the
+                // descriptor was made non-blocking by someone else
+                Some(libc::EIO) | Some(libc::EAGAIN) => return Ok(0),
+                _ => return Err(err.into()),
+            }
+        }
+        Ok(len as usize)
+    }
+}
+
+impl AsRawFd for UnixMasterPty {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.0.as_raw_fd()
+    }
 }
 
 impl Write for UnixMasterPty {
@@ -331,3 +627,689 @@ impl Write for UnixMasterPty {
         self.fd.flush()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Signal;
+
+    #[test]
+    fn winsize_includes_pixels() {
+        let size = PtySize {
+            rows: 24,
+            cols: 80,
+            pixel_width: 640,
+            pixel_height: 384,
+        };
+        let pair = UnixPtySystem::default().openpty(size).unwrap();
+        // get_size reads the winsize back via TIOCGWINSZ
+        assert_eq!(pair.master.get_size().unwrap(), size);
+
+        let size = PtySize {
+            rows: 30,
+            cols: 100,
+            pixel_width: 800,
+            pixel_height: 480,
+        };
+        pair.master.resize(size).unwrap();
+        assert_eq!(pair.master.get_size().unwrap(), size);
+    }
+
+    #[test]
+    fn spawn_error_omits_env_values() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        // A directory exists, so it gets as far as trying to exec it
+        let mut cmd = CommandBuilder::new("/");
+        cmd.env("SECRET_TOKEN", "hunter2");
+        let err = pair.slave.spawn_command(cmd).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(
+            matches!(err.downcast_ref::<PtyError>(), Some(PtyError::Spawn { .. })),
+            "unexpected error {}",
+            message
+        );
+        assert!(message.contains("\"/\""), "message was {:?}", message);
+        assert!(!message.contains("hunter2"), "message was {:?}", message);
+    }
+
+    /// Reads from the pty until the output contains `needle`
+    fn read_until(rx: &std::sync::mpsc::Receiver<String>, output: &mut String, needle: &str) {
+        while !output.contains(needle) {
+            let chunk = rx
+                .recv_timeout(std::time::Duration::from_secs(10))
+                .unwrap_or_else(|_| panic!("didn't see {:?} in {:?}", needle, output));
+            output.push_str(&chunk);
+        }
+    }
+
+    /// Forwards the output of the pty to the returned channel
+    fn spawn_reader(master: &dyn MasterPty) -> std::sync::mpsc::Receiver<String> {
+        let mut reader = master.try_clone_reader().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            loop {
+                let len = match reader.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(len) => len,
+                };
+                let chunk = String::from_utf8_lossy(&buf[..len]).into_owned();
+                if tx.send(chunk).is_err() {
+                    break;
+                }
+            }
+        });
+        rx
+    }
+
+    #[test]
+    fn resize_delivers_sigwinch() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let mut cmd = CommandBuilder::new("sh");
+        cmd.args(&[
+            "-c",
+            "n=0; trap 'n=$((n+1)); echo winch $n' WINCH; echo ready; \
+             while [ $n -lt 3 ]; do sleep 0.1; done",
+        ]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let rx = spawn_reader(&*pair.master);
+
+        let mut output = String::new();
+        read_until(&rx, &mut output, "ready");
+        for (n, cols) in [(1, 100), (2, 120)].iter() {
+            pair.master
+                .resize(PtySize {
+                    cols: *cols,
+                    ..PtySize::default()
+                })
+                .unwrap();
+            read_until(&rx, &mut output, &format!("winch {}", n));
+        }
+        // An explicit resend is delivered even though the size is unchanged
+        pair.master.signal_window_change().unwrap();
+        read_until(&rx, &mut output, "winch 3");
+
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn send_signal_to_shell() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let mut cmd = CommandBuilder::new("sh");
+        cmd.args(&[
+            "-c",
+            "trap 'echo hup' HUP; trap 'echo int; exit 3' INT; echo ready; \
+             while :; do sleep 0.1; done",
+        ]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let rx = spawn_reader(&*pair.master);
+
+        let mut output = String::new();
+        read_until(&rx, &mut output, "ready");
+        child.send_signal(Signal::Hangup).unwrap();
+        read_until(&rx, &mut output, "hup");
+        child.send_signal(Signal::Interrupt).unwrap();
+        read_until(&rx, &mut output, "int");
+        assert_eq!(child.wait().unwrap().exit_code(), 3);
+    }
+
+    #[test]
+    fn wait_and_drain() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let mut cmd = CommandBuilder::new("echo");
+        cmd.arg("hello");
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let (status, output) = pair.wait_and_drain(&mut *child).unwrap();
+        assert!(status.success());
+        assert_eq!(output, b"hello\r\n");
+    }
+
+    #[test]
+    fn send_eof() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let mut cmd = CommandBuilder::new("sh");
+        cmd.args([
+            "-c",
+            "read line; echo \"got $line\"; if read more; then echo more; else echo eof; fi",
+        ]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let rx = spawn_reader(&*pair.master);
+
+        // The slave is still alive, but that doesn't keep input open
+        pair.master.write_all_pty(b"hello\n").unwrap();
+        pair.master.send_eof().unwrap();
+        let mut output = String::new();
+        read_until(&rx, &mut output, "got hello");
+        read_until(&rx, &mut output, "eof");
+        assert!(!output.contains("more"), "output was {:?}", output);
+        assert!(child.wait().unwrap().success());
+
+        pair.slave.set_raw().unwrap();
+        assert!(pair.master.send_eof().is_err());
+    }
+
+    #[test]
+    fn channel_reader() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let mut child = pair
+            .slave
+            .spawn_command(CommandBuilder::new("yes"))
+            .unwrap();
+        let rx = pair.master.channel_reader(2).unwrap();
+        for _ in 0..3 {
+            let chunk = rx.recv().unwrap().unwrap();
+            assert!(chunk.split(|&b| b == b'\n').any(|line| line == b"y\r"));
+        }
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn initial_input() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let mut cmd = CommandBuilder::new("sh");
+        cmd.initial_input("echo $((6 * 7))\nexit 5\n");
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let (status, output) = pair.wait_and_drain(&mut *child).unwrap();
+        assert_eq!(status.exit_code(), 5);
+        let output = String::from_utf8_lossy(&output);
+        assert!(output.contains("42\r\n"), "output was {:?}", output);
+    }
+
+    #[test]
+    fn large_initial_input_does_not_block_spawn() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        // sleep never reads its input, so this can't all be written
+        let mut cmd = CommandBuilder::new("sleep");
+        cmd.arg("10");
+        cmd.initial_input(vec![b'x'; 64 * 1024]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn initial_input_after_master_dropped() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        drop(pair.master);
+        let mut cmd = CommandBuilder::new("true");
+        cmd.initial_input("hello\n");
+        assert!(pair.slave.spawn_command(cmd).is_err());
+    }
+
+    #[test]
+    fn arg0_login_shell() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let mut cmd = CommandBuilder::new("sh");
+        cmd.arg0("-sh");
+        cmd.args(&["-c", "echo $0"]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let (status, output) = pair.wait_and_drain(&mut *child).unwrap();
+        assert!(status.success());
+        // A login shell may print something from the profile first
+        let output = String::from_utf8_lossy(&output);
+        assert!(output.ends_with("-sh\r\n"), "output was {:?}", output);
+    }
+
+    #[test]
+    fn read_from_master() {
+        let mut pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let mut cmd = CommandBuilder::new("echo");
+        cmd.arg("hello");
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        drop(pair.slave);
+        let mut output = String::new();
+        pair.master.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "hello\r\n");
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn poll_raw_fd() {
+        let (master, slave) = openpty(PtySize::default()).unwrap();
+        let fd = AsRawFd::as_raw_fd(&master);
+        assert_eq!(MasterPty::as_raw_fd(&master), Some(fd));
+
+        let mut pfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        assert_eq!(unsafe { libc::poll(&mut pfd, 1, 0) }, 0);
+
+        let mut cmd = CommandBuilder::new("echo");
+        cmd.arg("hello");
+        let mut child = slave.spawn_command(cmd).unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(unsafe { libc::poll(&mut pfd, 1, 10_000) }, 1);
+        assert!(pfd.revents & libc::POLLIN != 0);
+    }
+
+    #[test]
+    fn exit_status_signal() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+
+        let mut cmd = CommandBuilder::new("sh");
+        cmd.args(&["-c", "exit 3"]);
+        let status = pair.slave.spawn_command(cmd).unwrap().wait().unwrap();
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(status.signal(), None);
+        assert!(!status.was_terminated());
+
+        let mut cmd = CommandBuilder::new("sleep");
+        cmd.arg("10");
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        child.send_signal(Signal::Kill).unwrap();
+        let status = child.wait().unwrap();
+        assert!(!status.success());
+        assert_eq!(status.code(), None);
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+        assert!(status.was_terminated());
+    }
+
+    #[test]
+    fn kill_gracefully() {
+        use std::time::Duration;
+
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let rx = spawn_reader(&*pair.master);
+
+        // Exits cleanly in response to SIGTERM
+        let mut cmd = CommandBuilder::new("sh");
+        cmd.args(&[
+            "-c",
+            "trap 'exit 3' TERM; echo ready; while :; do sleep 0.1; done",
+        ]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let mut output = String::new();
+        read_until(&rx, &mut output, "ready");
+        let status = child.kill_gracefully(Duration::from_secs(10)).unwrap();
+        assert_eq!(status.code(), Some(3));
+
+        // Ignores SIGTERM, so it is killed once the grace period expires
+        let mut cmd = CommandBuilder::new("sh");
+        cmd.args(&["-c", "trap '' TERM; echo set; while :; do sleep 0.1; done"]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        read_until(&rx, &mut output, "set");
+        let status = child.kill_gracefully(Duration::from_millis(200)).unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+
+        // Already exited
+        let mut child = pair
+            .slave
+            .spawn_command(CommandBuilder::new("true"))
+            .unwrap();
+        child.wait().unwrap();
+        assert!(child
+            .kill_gracefully(Duration::from_secs(10))
+            .unwrap()
+            .success());
+    }
+
+    #[test]
+    fn current_working_dir() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let rx = spawn_reader(&*pair.master);
+        let start = std::env::temp_dir().canonicalize().unwrap();
+
+        let mut cmd = CommandBuilder::new("sh");
+        cmd.args(&["-c", "echo started; read x; cd /; echo moved; read y"]);
+        cmd.cwd(&start);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let mut output = String::new();
+        read_until(&rx, &mut output, "started");
+        assert_eq!(child.current_working_dir(), Some(start));
+
+        pair.master.write_all_pty(b"\n").unwrap();
+        read_until(&rx, &mut output, "moved");
+        assert_eq!(
+            child.current_working_dir(),
+            Some(std::path::PathBuf::from("/"))
+        );
+
+        pair.master.write_all_pty(b"\n").unwrap();
+        child.wait().unwrap();
+        assert_eq!(child.current_working_dir(), None);
+    }
+
+    #[test]
+    fn process_id_is_stable() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let mut cmd = CommandBuilder::new("sleep");
+        cmd.arg("10");
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let pid = child.process_id().unwrap();
+        let mut killer = child.clone_killer();
+        killer.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(child.process_id(), Some(pid));
+    }
+
+    #[test]
+    fn handle_clone_error() {
+        let master = UnixMasterPty {
+            fd: PtyFd(unsafe { FileDescriptor::from_raw_fd(-1) }),
+            size: Arc::new(Mutex::new(PtySize::default())),
+            input: Arc::new(unsafe { FileDescriptor::from_raw_fd(-1) }),
+        };
+        let err = master.try_clone_reader().err().unwrap();
+        match err.downcast_ref::<PtyError>() {
+            Some(PtyError::HandleClone {
+                handle, operation, ..
+            }) => {
+                assert_eq!(*handle, "pty master");
+                assert_eq!(*operation, "create a reader");
+            }
+            _ => panic!("unexpected error {:#}", err),
+        }
+    }
+
+    #[test]
+    fn new_process_group() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let mut cmd = CommandBuilder::new("sleep");
+        cmd.arg("10");
+        cmd.new_process_group(true);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let pid = child.process_id().unwrap() as libc::pid_t;
+        assert_eq!(unsafe { libc::getpgid(pid) }, pid);
+        assert_ne!(unsafe { libc::getpgid(pid) }, unsafe { libc::getpgrp() });
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn write_all_pty() {
+        let (master, slave) = openpty(PtySize::default()).unwrap();
+        // Without raw mode, the line discipline would discard input
+        // beyond the maximum line length
+        slave.set_raw().unwrap();
+        let mut slave_fd = slave.fd.try_clone().unwrap();
+        let data: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+        let expected = data.len();
+        let reader = std::thread::spawn(move || {
+            let mut received = vec![0u8; expected];
+            slave_fd.read_exact(&mut received).unwrap();
+            received
+        });
+        master.write_all_pty(&data).unwrap();
+        assert!(reader.join().unwrap() == data);
+    }
+
+    #[test]
+    fn get_size_queries_kernel() {
+        let (master, slave) = openpty(PtySize::default()).unwrap();
+        let size = PtySize {
+            rows: 50,
+            cols: 132,
+            pixel_width: 0,
+            pixel_height: 0,
+        };
+        // Resize via the slave, so that only the kernel knows about it
+        slave.fd.resize(size).unwrap();
+        assert_eq!(master.get_size().unwrap(), size);
+    }
+
+    #[test]
+    fn detach_does_not_kill() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let mut cmd = CommandBuilder::new("sleep");
+        cmd.arg("10");
+        cmd.kill_on_drop(true);
+        let child = pair.slave.spawn_command(cmd).unwrap();
+        let pid = child.process_id().unwrap() as libc::pid_t;
+        child.detach();
+
+        // The process is still running, rather than being a zombie
+        // that we could reap straight away
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) }, 0);
+
+        unsafe { libc::kill(pid, libc::SIGKILL) };
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    }
+
+    #[test]
+    fn read_available() {
+        let (master, slave) = openpty(PtySize::default()).unwrap();
+        let mut buf = [0u8; 64];
+        assert_eq!(master.read_available(&mut buf).unwrap(), 0);
+
+        let mut slave_fd = slave.fd.try_clone().unwrap();
+        slave_fd.write_all(b"hello").unwrap();
+        // The output reaches the master asynchronously
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        let mut output = vec![];
+        while output.len() < 5 {
+            assert!(std::time::Instant::now() < deadline, "got {:?}", output);
+            let len = master.read_available(&mut buf).unwrap();
+            output.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(output, b"hello");
+        assert_eq!(master.read_available(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn priority() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let mut cmd = CommandBuilder::new("sleep");
+        cmd.arg("10");
+        cmd.priority(Some(crate::PriorityClass::Idle));
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let pid = child.process_id().unwrap();
+        let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS as _, pid as _) };
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(nice, 19);
+    }
+
+    #[test]
+    fn inherit_fd() {
+        let mut pipe = filedescriptor::Pipe::new().unwrap();
+        let fd = pipe.write.as_raw_fd();
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let mut cmd = CommandBuilder::new("sh");
+        cmd.args(&["-c", &format!("echo hello > /dev/fd/{}", fd)]);
+        cmd.inherit_fd(fd);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        assert!(child.wait().unwrap().success());
+        drop(pipe.write);
+        let mut output = String::new();
+        pipe.read.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "hello\n");
+    }
+
+    #[test]
+    fn dropping_reader_keeps_others_working() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let first = pair.master.try_clone_reader().unwrap();
+        let mut second = pair.master.try_clone_reader().unwrap();
+        drop(first);
+
+        // The line discipline echoes input back to the master
+        pair.master.write_all_pty(b"hello\n").unwrap();
+        let mut buf = [0u8; 64];
+        let len = second.read(&mut buf).unwrap();
+        assert!(buf[..len].starts_with(b"hello"));
+        drop(second);
+
+        pair.master.write_all_pty(b"again\n").unwrap();
+        let mut third = pair.master.try_clone_reader().unwrap();
+        let len = third.read(&mut buf).unwrap();
+        assert!(buf[..len].starts_with(b"again"));
+    }
+
+    #[test]
+    fn buffer_size_matches_viewport() {
+        let size = PtySize {
+            rows: 40,
+            cols: 120,
+            pixel_width: 0,
+            pixel_height: 0,
+        };
+        let pair = UnixPtySystem::default().openpty(size).unwrap();
+        assert_eq!(pair.master.buffer_size().unwrap(), size);
+    }
+
+    #[test]
+    fn controlling_tty() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let mut cmd = CommandBuilder::new("sleep");
+        cmd.arg("10");
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        // The child is a session leader and the foreground process
+        // group of the pty, which is only the case if the slave is its
+        // controlling terminal
+        let pid = child.process_id().unwrap() as libc::pid_t;
+        let leader = pair.master.process_group_leader();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(leader, Some(pid));
+
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let mut cmd = CommandBuilder::new("sleep");
+        cmd.arg("10");
+        cmd.controlling_tty(false);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let pid = child.process_id().unwrap() as libc::pid_t;
+        let leader = pair.master.process_group_leader();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_ne!(leader, Some(pid));
+    }
+
+    #[test]
+    fn send_ctrl_c() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let mut cmd = CommandBuilder::new("sleep");
+        cmd.arg("10");
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        child.send_ctrl_c().unwrap();
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGINT));
+    }
+
+    #[test]
+    fn spawn_sequential_children() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        for code in 1..=2 {
+            let mut cmd = CommandBuilder::new("sh");
+            cmd.args(&["-c", format!("exit {}", code).as_str()]);
+            let mut child = pair.slave.spawn_command(cmd).unwrap();
+            assert_eq!(child.wait().unwrap().exit_code(), code);
+        }
+    }
+
+    #[test]
+    fn stderr_pipe() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let mut cmd = CommandBuilder::new("sh");
+        cmd.args(&["-c", "echo out; echo err >&2"]);
+        cmd.stderr(StdioMode::Pipe);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+
+        let mut stderr = String::new();
+        child
+            .take_stderr()
+            .unwrap()
+            .read_to_string(&mut stderr)
+            .unwrap();
+        assert_eq!(stderr, "err\n");
+        assert!(child.take_stderr().is_none());
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn slave_name() {
+        let pair = UnixPtySystem::default()
+            .openpty(PtySize::default())
+            .unwrap();
+        let name = pair.slave.name().unwrap();
+        assert!(name.starts_with("/dev/"), "name was {}", name);
+    }
+
+    fn echo_enabled(slave: &UnixSlavePty) -> bool {
+        let mut echo = false;
+        slave
+            .with_termios(&mut |t: &mut libc::termios| echo = t.c_lflag & libc::ECHO != 0)
+            .unwrap();
+        echo
+    }
+
+    #[test]
+    fn raw_and_cooked() {
+        let (mut master, slave) = openpty(PtySize::default()).unwrap();
+        let mut slave_fd = slave.fd.try_clone().unwrap();
+
+        slave.set_raw().unwrap();
+        assert!(!echo_enabled(&slave));
+        // Without canonical mode, input is available without a newline
+        master.write_all(b"abc").unwrap();
+        let mut buf = [0u8; 3];
+        slave_fd.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"abc");
+
+        slave.set_cooked().unwrap();
+        assert!(echo_enabled(&slave));
+        // and now the input is echoed back to the master
+        master.write_all(b"hi\n").unwrap();
+        let mut output = String::new();
+        while !output.contains("hi\r\n") {
+            let mut buf = [0u8; 64];
+            let len = master.fd.read(&mut buf).unwrap();
+            output.push_str(&String::from_utf8_lossy(&buf[..len]));
+        }
+        let mut line = [0u8; 3];
+        slave_fd.read_exact(&mut line).unwrap();
+        assert_eq!(&line, b"hi\n");
+    }
+}
@@ -1,91 +1,315 @@
 use crate::cmdbuilder::CommandBuilder;
-use crate::win::psuedocon::PsuedoCon;
-use crate::{Child, MasterPty, PtyPair, PtySize, PtySystem, SlavePty};
+use crate::win::psuedocon::{
+    PsuedoCon, PSEUDOCONSOLE_INHERIT_CURSOR, PSEUDOCONSOLE_RESIZE_QUIRK,
+    PSEUDOCONSOLE_WIN32_INPUT_MODE,
+};
+use crate::{
+    box_child, Child, InitialInput, MasterPty, PtyError, PtyHandleSet, PtyPair, PtySize, PtySystem,
+    ReadableToken, SlavePty,
+};
 use anyhow::Error;
 use filedescriptor::{FileDescriptor, Pipe};
-use std::io;
+use std::io::{self, Write};
+use std::os::windows::io::{AsRawHandle, RawHandle};
+use std::sync::mpsc::{channel, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use winapi::shared::minwindef::DWORD;
 use winapi::um::wincon::COORD;
 
+pub use crate::win::psuedocon::{conpty_is_available, load_conpty_from};
+
+impl From<PtySize> for COORD {
+    fn from(size: PtySize) -> COORD {
+        COORD {
+            X: size.cols as i16,
+            Y: size.rows as i16,
+        }
+    }
+}
+
+/// Conpty sizes are expressed as a COORD of signed 16 bit values and
+/// must be at least a single cell.  Sizes outside of that range would
+/// otherwise wrap around and fail with an opaque HRESULT.
+fn check_size(size: PtySize) -> Result<(), PtyError> {
+    let max = i16::MAX as u16;
+    for (dimension, value) in [("cols", size.cols), ("rows", size.rows)].iter() {
+        if *value == 0 || *value > max {
+            return Err(PtyError::InvalidSize {
+                dimension: *dimension,
+                value: *value,
+                max,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// The `PSEUDOCONSOLE_*` flags that are passed to `CreatePseudoConsole`
+/// when opening a pty, which influence how conpty renders output and
+/// interprets input.  The default produces the same behavior as
+/// previous versions of this crate.
+///
+/// Only `PSEUDOCONSOLE_INHERIT_CURSOR` is documented as part of the
+/// Windows SDK; the others are defined by the conpty implementation in
+/// the Windows Terminal repository.  A version of conpty that doesn't
+/// know a flag ignores it, so requesting a flag that isn't supported
+/// by the running system is harmless.  The in-box conpty is used unless
+/// another implementation was loaded via `load_conpty_from`, in which
+/// case its version is what matters.
+///
+/// Fields are added as conpty gains flags, so start from `Default` and
+/// assign the fields that you need.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ConPtyOptions {
+    /// `PSEUDOCONSOLE_INHERIT_CURSOR`: start at the cursor position of
+    /// the console that is hosting the pty; see
+    /// `ConPtySystem::set_inherit_cursor`.
+    /// Supported since Windows 10 1809 (build 17763).
+    /// The default is false.
+    pub inherit_cursor: bool,
+    /// `PSEUDOCONSOLE_RESIZE_QUIRK`: when the pty is resized, conpty
+    /// doesn't repaint the viewport itself but leaves reflowing the
+    /// existing output to the terminal, which avoids it emitting
+    /// spurious clears and duplicated lines that a terminal that
+    /// reflows on its own would otherwise show.  Disable it for
+    /// terminals that don't reflow their contents on resize.
+    /// Supported since Windows 11 (build 22000) and by the conpty of
+    /// Windows Terminal 1.0 and later; earlier builds always repaint.
+    /// The default is true.
+    pub resize_quirk: bool,
+    /// `PSEUDOCONSOLE_WIN32_INPUT_MODE`: see
+    /// `ConPtySystem::set_win32_input_mode`.
+    /// Supported since Windows 11 (build 22000) and by the conpty of
+    /// Windows Terminal 1.1 and later.
+    /// The default is true.
+    pub win32_input_mode: bool,
+}
+
+impl Default for ConPtyOptions {
+    fn default() -> Self {
+        Self {
+            inherit_cursor: false,
+            resize_quirk: true,
+            win32_input_mode: true,
+        }
+    }
+}
+
+impl ConPtyOptions {
+    fn flags(&self) -> DWORD {
+        let mut flags = 0;
+        if self.inherit_cursor {
+            flags |= PSEUDOCONSOLE_INHERIT_CURSOR;
+        }
+        if self.resize_quirk {
+            flags |= PSEUDOCONSOLE_RESIZE_QUIRK;
+        }
+        if self.win32_input_mode {
+            flags |= PSEUDOCONSOLE_WIN32_INPUT_MODE;
+        }
+        flags
+    }
+}
+
 #[derive(Default)]
-pub struct ConPtySystem {}
+pub struct ConPtySystem {
+    options: ConPtyOptions,
+    open_timeout: Option<Duration>,
+    pipe_buffer_size: u32,
+}
+
+impl ConPtySystem {
+    /// Creates a pty system that opens pseudoconsoles with the
+    /// specified options
+    pub fn with_options(options: ConPtyOptions) -> Self {
+        Self {
+            options,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the options that pseudoconsoles are opened with
+    pub fn options(&self) -> ConPtyOptions {
+        self.options
+    }
+
+    /// Replaces the options that pseudoconsoles are opened with.
+    /// Ptys that have already been opened are not affected.
+    pub fn set_options(&mut self, options: ConPtyOptions) {
+        self.options = options;
+    }
+
+    /// When set to true, the pseudoconsole will be created with the
+    /// `PSEUDOCONSOLE_INHERIT_CURSOR` flag so that it starts at the
+    /// cursor position of the console that is hosting it.
+    /// Conpty determines that position by emitting a cursor position
+    /// request (`CSI 6 n`) to the output pipe, so the application must
+    /// read the output and respond via the input pipe, otherwise the
+    /// pseudoconsole will stall waiting for the reply.
+    /// The default is false.
+    pub fn set_inherit_cursor(&mut self, inherit: bool) {
+        self.options.inherit_cursor = inherit;
+    }
+
+    /// Bounds how long `openpty` may take.  Loading conpty and creating
+    /// the pseudoconsole can occasionally stall, for example due to
+    /// interference from anti-virus software, which would otherwise
+    /// hang the calling thread.  When a timeout is set, the pty is
+    /// opened on a separate thread and `openpty` returns
+    /// `PtyError::OpenTimeout` if it doesn't complete in time; the pty
+    /// is closed if the thread eventually manages to open it.
+    /// The default is None, meaning no timeout.
+    pub fn set_open_timeout(&mut self, timeout: Option<Duration>) {
+        self.open_timeout = timeout;
+    }
+
+    /// Controls how conpty interprets the input written to the pty.
+    /// When enabled, conpty requests win32-input-mode from the terminal
+    /// (`CSI ? 9001 h`) and accepts key events encoded that way, which
+    /// lets it deliver complete key events, including modifiers and key
+    /// releases, to console programs that read input via
+    /// `ReadConsoleInput`.  Disable it if the embedding terminal doesn't
+    /// implement win32-input-mode; conpty then synthesizes key events
+    /// from plain VT input.
+    /// The default is true.
+    ///
+    /// The other console modes are not under the control of the host:
+    /// conpty always renders the output of the child as VT sequences,
+    /// so `ENABLE_VIRTUAL_TERMINAL_PROCESSING` is only needed by
+    /// programs that want to emit VT sequences of their own, and
+    /// programs that want to receive VT input instead of key events,
+    /// such as ssh or vim, must enable `ENABLE_VIRTUAL_TERMINAL_INPUT`
+    /// on their console input handle themselves via `SetConsoleMode`.
+    pub fn set_win32_input_mode(&mut self, enable: bool) {
+        self.options.win32_input_mode = enable;
+    }
+
+    /// Suggests the size, in bytes, of the buffers of the pipes that
+    /// carry input to and output from the pseudoconsole.
+    /// Programs that emit large bursts of output, such as `type` of a
+    /// big file, spend less time blocked waiting for the reader to
+    /// catch up when the buffer is larger.  The size is advisory and
+    /// may be rounded by the system.
+    /// The default is 0, which uses the system default size.
+    pub fn set_pipe_buffer_size(&mut self, size: u32) {
+        self.pipe_buffer_size = size;
+    }
+}
 
 impl PtySystem for ConPtySystem {
     fn openpty(&self, size: PtySize) -> anyhow::Result<PtyPair> {
-        let stdin = Pipe::new()?;
-        let stdout = Pipe::new()?;
-
-        let con = PsuedoCon::new(
-            COORD {
-                X: size.cols as i16,
-                Y: size.rows as i16,
-            },
-            stdin.read,
-            stdout.write,
-        )?;
-
-        let master = ConPtyMasterPty {
-            inner: Arc::new(Mutex::new(Inner {
-                con,
-                readable: stdout.read,
-                writable: stdin.write,
-                size,
-            })),
-        };
+        check_size(size)?;
+        let flags = self.options.flags();
+        let buffer_size = self.pipe_buffer_size;
 
-        let slave = ConPtySlavePty {
-            inner: master.inner.clone(),
+        let timeout = match self.open_timeout {
+            Some(timeout) => timeout,
+            None => return open(size, flags, buffer_size),
         };
 
-        Ok(PtyPair {
-            master: Box::new(master),
-            slave: Box::new(slave),
-        })
+        let (tx, rx) = channel();
+        std::thread::spawn(move || {
+            // If we timed out the receiver is gone and the pair is
+            // simply dropped here
+            tx.send(open(size, flags, buffer_size)).ok();
+        });
+        match rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(PtyError::OpenTimeout(timeout).into()),
+            Err(RecvTimeoutError::Disconnected) => {
+                anyhow::bail!("the thread opening the pty terminated unexpectedly")
+            }
+        }
     }
 }
 
+fn open(size: PtySize, flags: DWORD, buffer_size: u32) -> anyhow::Result<PtyPair> {
+    let stdin = Pipe::with_buffer_size(buffer_size).map_err(PtyError::pipe)?;
+    let stdout = Pipe::with_buffer_size(buffer_size).map_err(PtyError::pipe)?;
+
+    let con = PsuedoCon::new(size.into(), stdin.read, stdout.write, flags)?;
+
+    let master = ConPtyMasterPty {
+        inner: Arc::new(Mutex::new(Inner { con, size })),
+        reader: Arc::new(Mutex::new(stdout.read.try_clone().map_err(
+            PtyError::handle_clone("conpty output pipe", "read from the master"),
+        )?)),
+        writer: Arc::new(Mutex::new(stdin.write.try_clone().map_err(
+            PtyError::handle_clone("conpty input pipe", "write to the master"),
+        )?)),
+        readable: Arc::new(stdout.read),
+        writable: Arc::new(stdin.write),
+    };
+
+    let slave = ConPtySlavePty {
+        inner: master.inner.clone(),
+        writable: master.writable.clone(),
+    };
+
+    Ok(PtyPair {
+        master: Box::new(master),
+        slave: Box::new(slave),
+    })
+}
+
+/// The state that must be synchronized with resizing.  The pipes are
+/// kept separately so that reading, writing and cloning handles never
+/// wait for a resize, or for each other.
 struct Inner {
     con: PsuedoCon,
-    readable: FileDescriptor,
-    writable: FileDescriptor,
     size: PtySize,
 }
 
 impl Inner {
-    pub fn resize(
-        &mut self,
-        num_rows: u16,
-        num_cols: u16,
-        pixel_width: u16,
-        pixel_height: u16,
-    ) -> Result<(), Error> {
-        self.con.resize(COORD {
-            X: num_cols as i16,
-            Y: num_rows as i16,
+    /// ResizePseudoConsole only accepts a character cell COORD, so there
+    /// is no way to pass the pixel dimensions through to the child.
+    /// We still record them so that get_size reports what the
+    /// caller set.
+    pub fn resize(&mut self, size: PtySize) -> Result<(), Error> {
+        check_size(size)?;
+        self.con.resize(size.into()).map_err(|source| {
+            if PsuedoCon::is_closed_error(&source) {
+                PtyError::PtyClosed(source)
+            } else {
+                PtyError::Resize { size, source }
+            }
         })?;
-        self.size = PtySize {
-            rows: num_rows,
-            cols: num_cols,
-            pixel_width,
-            pixel_height,
-        };
+        self.size = size;
         Ok(())
     }
 }
 
+/// `inner` is declared first so that, if this holds the last reference
+/// to it, the pseudoconsole is closed before the pipes, as it was when
+/// the pipes were part of `Inner`.
 #[derive(Clone)]
 pub struct ConPtyMasterPty {
     inner: Arc<Mutex<Inner>>,
+    /// Used by `read_output`, separately from `inner` for the same
+    /// reason as `writer`
+    reader: Arc<Mutex<FileDescriptor>>,
+    /// Used by the Write impl.  This is separate from `inner` so that
+    /// a write that blocks because the child isn't reading its input
+    /// doesn't prevent resizing or cloning readers.
+    writer: Arc<Mutex<FileDescriptor>>,
+    /// The pipes connected to the pseudoconsole, from which readers and
+    /// writers are cloned.  Duplicating a handle doesn't require
+    /// exclusive access to it, so these don't need a lock.
+    readable: Arc<FileDescriptor>,
+    writable: Arc<FileDescriptor>,
 }
 
 pub struct ConPtySlavePty {
     inner: Arc<Mutex<Inner>>,
+    writable: Arc<FileDescriptor>,
 }
 
 impl MasterPty for ConPtyMasterPty {
     fn resize(&self, size: PtySize) -> anyhow::Result<()> {
         let mut inner = self.inner.lock().unwrap();
-        inner.resize(size.rows, size.cols, size.pixel_width, size.pixel_height)
+        inner.resize(size)
     }
 
     fn get_size(&self) -> Result<PtySize, Error> {
@@ -94,17 +318,58 @@ impl MasterPty for ConPtyMasterPty {
     }
 
     fn try_clone_reader(&self) -> anyhow::Result<Box<dyn std::io::Read + Send>> {
-        Ok(Box::new(self.inner.lock().unwrap().readable.try_clone()?))
+        Ok(Box::new(self.readable.try_clone().map_err(
+            PtyError::handle_clone("conpty output pipe", "create a reader"),
+        )?))
     }
 
     fn try_clone_writer(&self) -> anyhow::Result<Box<dyn std::io::Write + Send>> {
-        Ok(Box::new(self.inner.lock().unwrap().writable.try_clone()?))
+        Ok(Box::new(self.writable.try_clone().map_err(
+            PtyError::handle_clone("conpty input pipe", "create a writer"),
+        )?))
+    }
+
+    fn read_output(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.lock().unwrap();
+        io::Read::read(&mut *reader, buf)
+    }
+
+    fn read_available(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let mut reader = self.reader.lock().unwrap();
+        Ok(crate::win::read_available(&mut reader, buf)?)
+    }
+
+    fn readable_token(&self) -> anyhow::Result<ReadableToken> {
+        Ok(ReadableToken::new(self.readable.try_clone().map_err(
+            PtyError::handle_clone("conpty output pipe", "create a readable token"),
+        )?))
+    }
+
+    fn handle_set(&self) -> anyhow::Result<PtyHandleSet> {
+        Ok(PtyHandleSet {
+            read: self
+                .readable
+                .try_clone()
+                .map_err(PtyError::handle_clone("conpty output pipe", "share it"))?,
+            write: self
+                .writable
+                .try_clone()
+                .map_err(PtyError::handle_clone("conpty input pipe", "share it"))?,
+        })
+    }
+
+    fn as_raw_handles(&self) -> Option<(RawHandle, RawHandle)> {
+        Some((self.readable.as_raw_handle(), self.writable.as_raw_handle()))
+    }
+
+    fn send_eof(&self) -> anyhow::Result<()> {
+        self.write_all_pty(b"\x1a\r")
     }
 }
 
 impl io::Write for ConPtyMasterPty {
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
-        self.inner.lock().unwrap().writable.write(buf)
+        self.writer.lock().unwrap().write(buf)
     }
     fn flush(&mut self) -> Result<(), io::Error> {
         Ok(())
@@ -112,9 +377,595 @@ impl io::Write for ConPtyMasterPty {
 }
 
 impl SlavePty for ConPtySlavePty {
-    fn spawn_command(&self, cmd: CommandBuilder) -> anyhow::Result<Box<dyn Child + Send + Sync>> {
-        let inner = self.inner.lock().unwrap();
-        let child = inner.con.spawn_command(cmd)?;
-        Ok(Box::new(child))
+    fn spawn_command(
+        &self,
+        mut cmd: CommandBuilder,
+    ) -> anyhow::Result<Box<dyn Child + Send + Sync>> {
+        let kill_on_drop = cmd.kill_on_drop;
+        let initial_input = if cmd.initial_input.is_empty() {
+            None
+        } else {
+            let writer = self.writable.try_clone().map_err(PtyError::handle_clone(
+                "conpty input pipe",
+                "write the initial input",
+            ))?;
+            Some(InitialInput::new(
+                std::mem::take(&mut cmd.initial_input),
+                writer,
+            ))
+        };
+        let mut child = self.inner.lock().unwrap().con.spawn_command(cmd)?;
+        child.input = Some(Arc::downgrade(&self.writable));
+        let mut child = box_child(child, kill_on_drop);
+        if let Some(initial_input) = initial_input {
+            // The pseudoconsole buffers this until the child reads it
+            initial_input.write(&mut *child)?;
+        }
+        Ok(child)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resize_preserves_pixel_size() {
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let size = PtySize {
+            rows: 30,
+            cols: 100,
+            pixel_width: 800,
+            pixel_height: 600,
+        };
+        pair.master.resize(size).unwrap();
+        assert_eq!(pair.master.get_size().unwrap(), size);
+    }
+
+    fn assert_invalid_size(err: anyhow::Error, expected: &str) {
+        match err.downcast_ref::<PtyError>() {
+            Some(PtyError::InvalidSize { dimension, .. }) => assert_eq!(*dimension, expected),
+            _ => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn zero_size_is_rejected() {
+        let size = PtySize {
+            rows: 0,
+            ..PtySize::default()
+        };
+        let err = ConPtySystem::default().openpty(size).err().unwrap();
+        assert_invalid_size(err, "rows");
+
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let size = PtySize {
+            cols: 0,
+            ..PtySize::default()
+        };
+        assert_invalid_size(pair.master.resize(size).unwrap_err(), "cols");
+        assert_eq!(pair.master.get_size().unwrap(), PtySize::default());
+    }
+
+    #[test]
+    fn oversized_is_rejected() {
+        let size = PtySize {
+            cols: 40000,
+            ..PtySize::default()
+        };
+        let err = ConPtySystem::default().openpty(size).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "requested 40000 cols is outside the supported range of 1 to 32767"
+        );
+        assert_invalid_size(err, "cols");
+    }
+
+    #[test]
+    fn spawn_sequential_children() {
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        for code in 1..=2 {
+            let mut cmd = CommandBuilder::new("cmd.exe");
+            cmd.args(&["/c", format!("exit {}", code).as_str()]);
+            let mut child = pair.slave.spawn_command(cmd).unwrap();
+            assert_eq!(child.wait().unwrap().exit_code(), code);
+        }
+    }
+
+    #[test]
+    fn spawn_error_preserves_unicode() {
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let cmd = CommandBuilder::new("C:\\does-not-exist\\ünïcødé-程序.exe");
+        let err = pair.slave.spawn_command(cmd).unwrap_err();
+        match err.downcast_ref::<PtyError>() {
+            Some(PtyError::Spawn { command, .. }) => {
+                assert_eq!(command, "C:\\does-not-exist\\ünïcødé-程序.exe")
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+        assert!(
+            err.to_string().contains("ünïcødé-程序"),
+            "error was {}",
+            err
+        );
+    }
+
+    #[test]
+    fn dropping_reader_keeps_others_working() {
+        use std::io::Read;
+
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let first = pair.master.try_clone_reader().unwrap();
+        let mut second = pair.master.try_clone_reader().unwrap();
+        drop(first);
+
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.args(&["/c", "echo hello"]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let mut buf = [0u8; 4096];
+        assert!(second.read(&mut buf).unwrap() > 0);
+        drop(second);
+
+        // A reader obtained after the others were dropped also works
+        let mut third = pair.master.try_clone_reader().unwrap();
+        std::thread::spawn(move || {
+            std::io::copy(&mut third, &mut std::io::sink()).ok();
+        });
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn handle_set_duplicate_into() {
+        use crate::handles::PtyHandleSet;
+        use winapi::um::processthreadsapi::GetCurrentProcess;
+
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let handles = pair.master.handle_set().unwrap();
+        let raw = handles
+            .duplicate_into(unsafe { GetCurrentProcess() } as _)
+            .unwrap();
+        // The duplicates are independent of the originals
+        drop(handles);
+        let mut dup = unsafe { PtyHandleSet::from_raw(raw) };
+
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.args(&["/c", "echo hello"]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let mut buf = [0u8; 4096];
+        assert!(io::Read::read(&mut dup.read, &mut buf).unwrap() > 0);
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn concurrent_read_write_and_resize() {
+        use std::io::{Read, Write};
+
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.args(&["/c", "for /l %i in (1,1,5000) do @echo line %i"]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+
+        let mut reader = pair.master.try_clone_reader().unwrap();
+        let reader = std::thread::spawn(move || {
+            let mut output = vec![];
+            reader.read_to_end(&mut output).unwrap();
+            output
+        });
+
+        let mut writer = pair.master.try_clone_writer().unwrap();
+        std::thread::spawn(move || {
+            // The child never reads its input, so these writes may block
+            // once the input buffer is full, which must not hold up
+            // reading or resizing.  The thread is released when the
+            // pty is closed.
+            let chunk = [b'x'; 1024];
+            for _ in 0..1024 {
+                if writer.write_all(&chunk).is_err() {
+                    break;
+                }
+            }
+        });
+
+        for cols in 60..100 {
+            pair.master
+                .resize(PtySize {
+                    cols,
+                    ..PtySize::default()
+                })
+                .unwrap();
+            let _ = pair.master.try_clone_reader().unwrap();
+        }
+
+        assert!(child
+            .wait_timeout(Duration::from_secs(60))
+            .unwrap()
+            .is_some());
+        drop(pair);
+        let output = String::from_utf8_lossy(&reader.join().unwrap()).to_string();
+        assert!(output.contains("line 5000"), "output was {:?}", output);
+    }
+
+    #[test]
+    fn was_terminated() {
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+
+        // Exiting with the same code as a terminated process
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.args(&["/c", "exit 1"]);
+        let status = pair.slave.spawn_command(cmd).unwrap().wait().unwrap();
+        assert_eq!(status.exit_code(), 1);
+        assert!(!status.was_terminated());
+
+        let mut cmd = CommandBuilder::new("ping.exe");
+        cmd.args(&["-t", "127.0.0.1"]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        child.clone_killer().kill().unwrap();
+        let status = child.wait().unwrap();
+        assert!(!status.success());
+        assert!(status.was_terminated());
+    }
+
+    #[test]
+    fn spawn_suspended() {
+        use std::time::Duration;
+
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.args(&["/c", "exit 3"]);
+        cmd.suspended(true);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        assert!(child
+            .wait_timeout(Duration::from_millis(200))
+            .unwrap()
+            .is_none());
+        child.resume().unwrap();
+        assert_eq!(child.wait().unwrap().exit_code(), 3);
+        assert!(child.resume().is_err());
+    }
+
+    #[test]
+    fn inherit_handle() {
+        use winapi::um::handleapi::GetHandleInformation;
+        use winapi::um::winbase::HANDLE_FLAG_INHERIT;
+
+        let pipe = filedescriptor::Pipe::new().unwrap();
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.args(&["/c", "exit 5"]);
+        cmd.inherit_handle(pipe.write.as_raw_handle());
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        assert_eq!(child.wait().unwrap().exit_code(), 5);
+
+        // The handle is only inheritable while spawning
+        let mut flags = 0;
+        let res = unsafe { GetHandleInformation(pipe.write.as_raw_handle() as _, &mut flags) };
+        assert_ne!(res, 0);
+        assert_eq!(flags & HANDLE_FLAG_INHERIT, 0);
+    }
+
+    #[test]
+    fn spawn_with_token() {
+        use filedescriptor::OwnedHandle;
+        use std::os::windows::io::FromRawHandle;
+        use winapi::um::processthreadsapi::{GetCurrentProcess, OpenProcessToken};
+        use winapi::um::winnt::{TOKEN_ASSIGN_PRIMARY, TOKEN_DUPLICATE, TOKEN_QUERY};
+
+        let mut token = std::ptr::null_mut();
+        let res = unsafe {
+            OpenProcessToken(
+                GetCurrentProcess(),
+                TOKEN_QUERY | TOKEN_DUPLICATE | TOKEN_ASSIGN_PRIMARY,
+                &mut token,
+            )
+        };
+        assert_ne!(res, 0, "{}", io::Error::last_os_error());
+        let token = unsafe { OwnedHandle::from_raw_handle(token as _) };
+
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.args(&["/c", "exit 4"]);
+        cmd.with_token(token.as_raw_handle());
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        assert_eq!(child.wait().unwrap().exit_code(), 4);
+    }
+
+    #[test]
+    fn without_win32_input_mode() {
+        let mut system = ConPtySystem::default();
+        system.set_win32_input_mode(false);
+        let pair = system.openpty(PtySize::default()).unwrap();
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.args(&["/c", "echo hello"]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let (status, output) = pair.wait_and_drain(&mut *child).unwrap();
+        assert!(status.success());
+        let output = String::from_utf8_lossy(&output);
+        assert!(output.contains("hello"), "output was {:?}", output);
+        assert!(!output.contains("\x1b[?9001h"), "output was {:?}", output);
+    }
+
+    #[test]
+    fn options() {
+        assert_eq!(
+            ConPtyOptions::default().flags(),
+            PSEUDOCONSOLE_RESIZE_QUIRK | PSEUDOCONSOLE_WIN32_INPUT_MODE
+        );
+
+        let options = ConPtyOptions {
+            resize_quirk: false,
+            win32_input_mode: false,
+            ..ConPtyOptions::default()
+        };
+        assert_eq!(options.flags(), 0);
+        let system = ConPtySystem::with_options(options);
+        assert_eq!(system.options(), options);
+        let pair = system.openpty(PtySize::default()).unwrap();
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.args(&["/c", "echo hello"]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let (status, output) = pair.wait_and_drain(&mut *child).unwrap();
+        assert!(status.success());
+        let output = String::from_utf8_lossy(&output);
+        assert!(output.contains("hello"), "output was {:?}", output);
+    }
+
+    #[test]
+    fn large_pipe_buffer() {
+        let mut system = ConPtySystem::default();
+        system.set_pipe_buffer_size(1024 * 1024);
+        let pair = system.openpty(PtySize::default()).unwrap();
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.args(&["/c", "for /l %i in (1,1,2000) do @echo line %i"]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let (status, output) = pair.wait_and_drain(&mut *child).unwrap();
+        assert!(status.success());
+        let output = String::from_utf8_lossy(&output);
+        assert!(output.contains("line 2000"), "output was {:?}", output);
+    }
+
+    #[test]
+    fn poller() {
+        use crate::PtyPoller;
+
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let mut poller = PtyPoller::new();
+        poller.register(7, pair.master.readable_token().unwrap());
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.args(&["/c", "echo hello"]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        assert_eq!(poller.poll(Some(Duration::from_secs(10))).unwrap(), vec![7]);
+        let mut buf = [0u8; 4096];
+        assert!(pair.master.read_available(&mut buf).unwrap() > 0);
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn send_ctrl_c() {
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let mut reader = pair.master.try_clone_reader().unwrap();
+        std::thread::spawn(move || {
+            // Keep the output flowing so that conpty doesn't stall
+            std::io::copy(&mut reader, &mut std::io::sink()).ok();
+        });
+        let mut cmd = CommandBuilder::new("ping.exe");
+        cmd.args(&["-t", "127.0.0.1"]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(child.try_wait().unwrap().is_none());
+        child.send_ctrl_c().unwrap();
+        assert!(child
+            .wait_timeout(Duration::from_secs(10))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn wait_and_drain() {
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.args(&["/c", "ver"]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let (status, output) = pair.wait_and_drain(&mut *child).unwrap();
+        assert!(status.success());
+        let output = String::from_utf8_lossy(&output);
+        assert!(
+            output.contains("Microsoft Windows [Version"),
+            "output was {:?}",
+            output
+        );
+    }
+
+    #[test]
+    fn reader_sees_eof_after_child_exits() {
+        use std::io::Read;
+        use std::sync::mpsc::channel;
+        use std::time::Duration;
+
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let mut reader = pair.master.try_clone_reader().unwrap();
+        let (tx, rx) = channel();
+        std::thread::spawn(move || {
+            let mut output = String::new();
+            let res = reader.read_to_string(&mut output).map(|_| output);
+            tx.send(res).ok();
+        });
+
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.args(&["/c", "echo hi"]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        assert!(child.wait().unwrap().success());
+        drop(pair);
+
+        let output = rx
+            .recv_timeout(Duration::from_secs(10))
+            .expect("reader didn't see EOF")
+            .unwrap();
+        assert!(output.contains("hi"), "output was {:?}", output);
+    }
+
+    #[test]
+    fn child_does_not_keep_pty_alive() {
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let mut reader = pair.master.try_clone_reader().unwrap();
+        std::thread::spawn(move || std::io::copy(&mut reader, &mut std::io::sink()));
+
+        let mut cmd = CommandBuilder::new("ping.exe");
+        cmd.args(&["-n", "60", "127.0.0.1"]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        drop(pair);
+
+        // Closing the pseudoconsole closes the processes attached to it
+        let status = child.wait_timeout(Duration::from_secs(10)).unwrap();
+        assert!(status.is_some(), "the pseudoconsole is still open");
+        assert!(child.send_ctrl_c().is_err());
+    }
+
+    #[test]
+    fn initial_input() {
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.initial_input("set /a 6*7\rexit 5\r");
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let (status, output) = pair.wait_and_drain(&mut *child).unwrap();
+        assert_eq!(status.exit_code(), 5);
+        let output = String::from_utf8_lossy(&output);
+        assert!(output.contains("42"), "output was {:?}", output);
+    }
+
+    #[test]
+    fn current_working_dir() {
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let mut reader = pair.master.try_clone_reader().unwrap();
+        std::thread::spawn(move || std::io::copy(&mut reader, &mut std::io::sink()));
+
+        let dir = std::env::current_dir().unwrap();
+        let mut cmd = CommandBuilder::new("ping.exe");
+        cmd.args(&["-n", "60", "127.0.0.1"]);
+        cmd.cwd(&dir);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        let cwd = child.current_working_dir().unwrap();
+        assert!(
+            cwd.to_string_lossy()
+                .eq_ignore_ascii_case(&dir.to_string_lossy()),
+            "{} != {}",
+            cwd.display(),
+            dir.display()
+        );
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn kill_gracefully() {
+        use std::time::Duration;
+
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let mut reader = pair.master.try_clone_reader().unwrap();
+        std::thread::spawn(move || std::io::copy(&mut reader, &mut std::io::sink()));
+
+        // ping stops as soon as it receives CTRL_C_EVENT
+        let mut cmd = CommandBuilder::new("ping.exe");
+        cmd.args(&["-n", "60", "127.0.0.1"]);
+        let mut child = pair.slave.spawn_command(cmd).unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+        let status = child.kill_gracefully(Duration::from_secs(10)).unwrap();
+        assert!(!status.was_terminated());
+    }
+
+    #[test]
+    fn send_eof() {
+        use std::time::Duration;
+
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let mut reader = pair.master.try_clone_reader().unwrap();
+        std::thread::spawn(move || std::io::copy(&mut reader, &mut std::io::sink()));
+
+        // sort only produces output and exits once its input reaches EOF
+        let mut child = pair
+            .slave
+            .spawn_command(CommandBuilder::new("sort.exe"))
+            .unwrap();
+        child.release_input();
+        assert!(child.send_ctrl_c().is_err());
+        pair.master.write_all_pty(b"b\ra\r").unwrap();
+        pair.master.send_eof().unwrap();
+        let status = child
+            .wait_timeout(Duration::from_secs(10))
+            .unwrap()
+            .expect("sort didn't see EOF");
+        assert!(status.success());
+    }
+
+    #[test]
+    fn code_page() {
+        use std::io::Read;
+
+        // 0x82 is é in code page 437 and ‚ in code page 1252
+        let path = std::env::temp_dir().join("portable-pty-code-page.txt");
+        std::fs::write(&path, b"\x82\r\n").unwrap();
+
+        for (code_page, expected) in &[(437, "é"), (1252, "‚")] {
+            let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+            let mut reader = pair.master.try_clone_reader().unwrap();
+            let output = std::thread::spawn(move || {
+                let mut output = String::new();
+                reader.read_to_string(&mut output).ok();
+                output
+            });
+
+            let mut cmd = CommandBuilder::new("cmd.exe");
+            cmd.arg("/c");
+            cmd.arg(format!("type {}", path.display()));
+            cmd.code_page(*code_page);
+            let mut child = pair.slave.spawn_command(cmd).unwrap();
+            assert!(child.wait().unwrap().success());
+            drop(pair);
+
+            // The output is always UTF-8, whatever the code page
+            let output = output.join().unwrap();
+            assert!(
+                output.contains(expected),
+                "output for {} was {:?}",
+                code_page,
+                output
+            );
+        }
+        std::fs::remove_file(&path).ok();
+
+        let pair = ConPtySystem::default().openpty(PtySize::default()).unwrap();
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.code_page(12345);
+        let err = pair.slave.spawn_command(cmd).unwrap_err();
+        assert!(err.to_string().contains("12345"), "error was {}", err);
+    }
+
+    #[test]
+    fn resize_after_close() {
+        use std::time::Instant;
+
+        // The pseudoconsole shuts down once the pipes connecting it to
+        // the terminal are closed
+        let stdin = Pipe::new().unwrap();
+        let stdout = Pipe::new().unwrap();
+        let size = PtySize::default();
+        let con = PsuedoCon::new(size.into(), stdin.read, stdout.write, 0).unwrap();
+        drop(stdin.write);
+        drop(stdout.read);
+        let mut inner = Inner { con, size };
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let err = loop {
+            match inner.resize(size) {
+                Ok(()) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(50))
+                }
+                Ok(()) => panic!("the pseudoconsole didn't shut down"),
+                Err(err) => break err,
+            }
+        };
+        match err.downcast_ref::<PtyError>() {
+            Some(PtyError::PtyClosed(_)) => {}
+            _ => panic!("unexpected error {:?}", err),
+        }
     }
 }
@@ -0,0 +1,68 @@
+use anyhow::{ensure, Error};
+use filedescriptor::OwnedHandle;
+use std::io::Error as IoError;
+use std::os::windows::io::{AsRawHandle, FromRawHandle};
+use std::{mem, ptr};
+use winapi::um::jobapi2::*;
+use winapi::um::winnt::{
+    JobObjectExtendedLimitInformation, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+    JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+};
+
+/// Create a Job Object that will terminate all of its member processes
+/// when the last handle to it is closed, and assign `proc` to it.
+pub fn create_kill_on_close_job(proc: &OwnedHandle) -> Result<OwnedHandle, Error> {
+    let job = unsafe { CreateJobObjectW(ptr::null_mut(), ptr::null()) };
+    ensure!(
+        !job.is_null(),
+        "CreateJobObjectW failed: {}",
+        IoError::last_os_error()
+    );
+    let job = unsafe { OwnedHandle::from_raw_handle(job as _) };
+
+    let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = unsafe { mem::zeroed() };
+    info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+    let res = unsafe {
+        SetInformationJobObject(
+            job.as_raw_handle() as _,
+            JobObjectExtendedLimitInformation,
+            &mut info as *mut _ as *mut _,
+            mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+        )
+    };
+    ensure!(
+        res != 0,
+        "SetInformationJobObject failed: {}",
+        IoError::last_os_error()
+    );
+
+    let res =
+        unsafe { AssignProcessToJobObject(job.as_raw_handle() as _, proc.as_raw_handle() as _) };
+    ensure!(
+        res != 0,
+        "AssignProcessToJobObject failed: {}",
+        IoError::last_os_error()
+    );
+
+    Ok(job)
+}
+
+/// Clear the limits on a job created by `create_kill_on_close_job`,
+/// so that closing it no longer terminates its member processes.
+pub fn disable_kill_on_close(job: &OwnedHandle) -> Result<(), Error> {
+    let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = unsafe { mem::zeroed() };
+    let res = unsafe {
+        SetInformationJobObject(
+            job.as_raw_handle() as _,
+            JobObjectExtendedLimitInformation,
+            &mut info as *mut _ as *mut _,
+            mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+        )
+    };
+    ensure!(
+        res != 0,
+        "SetInformationJobObject failed: {}",
+        IoError::last_os_error()
+    );
+    Ok(())
+}
@@ -1,52 +1,204 @@
-use crate::{Child, ChildKiller, ExitStatus};
+use crate::{
+    unsupported_signal, windows_handle_is_alive, Child, ChildKiller, ExitStatus, PtyError, Signal,
+};
 use anyhow::Context as _;
-use std::io::{Error as IoError, Result as IoResult};
+use std::convert::TryFrom;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult, Write};
 use std::os::windows::io::{AsRawHandle, RawHandle};
 use std::pin::Pin;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 use std::task::{Context, Poll};
+use std::time::Duration;
 use winapi::shared::minwindef::DWORD;
-use winapi::um::minwinbase::STILL_ACTIVE;
+use winapi::shared::winerror::{ERROR_BROKEN_PIPE, WAIT_TIMEOUT};
+use winapi::um::jobapi2::TerminateJobObject;
+use winapi::um::namedpipeapi::PeekNamedPipe;
 use winapi::um::processthreadsapi::*;
 use winapi::um::synchapi::WaitForSingleObject;
-use winapi::um::winbase::INFINITE;
+use winapi::um::winbase::{INFINITE, WAIT_OBJECT_0};
+use winapi::um::wincon::{GenerateConsoleCtrlEvent, CTRL_BREAK_EVENT};
 
 pub mod conpty;
+mod job;
+mod peb;
 mod procthreadattr;
 mod psuedocon;
+#[cfg(feature = "winpty")]
+pub mod winpty;
 
-use filedescriptor::OwnedHandle;
+use filedescriptor::{FileDescriptor, OwnedHandle};
+pub(crate) use peb::current_working_dir;
 
 #[derive(Debug)]
 pub struct WinChild {
     proc: Mutex<OwnedHandle>,
+    /// Captured at spawn time so that it remains available
+    /// even after the process has exited
+    pid: u32,
+    /// When set, the process was assigned to this kill-on-close job
+    job: Option<OwnedHandle>,
+    /// The input side of the pseudoconsole, used to deliver interrupts.
+    /// This is weak so that the child doesn't keep the pty alive.
+    input: Option<Weak<FileDescriptor>>,
+    /// The read end of stderr, when it was configured as a pipe
+    stderr: Option<FileDescriptor>,
+    /// The main thread, when the process was started suspended or
+    /// the caller asked for it to be retained
+    main_thread: Option<OwnedHandle>,
+    /// True until a process that was started suspended is resumed
+    suspended: bool,
+    /// Set once we have terminated the process; shared with killers
+    terminated: Arc<AtomicBool>,
+}
+
+/// The exit code of processes that we terminate
+const TERMINATED_EXIT_CODE: u32 = 1;
+
+/// Terminate the process, or the whole job if we have one
+fn terminate(
+    proc: &OwnedHandle,
+    job: Option<&OwnedHandle>,
+    terminated: &AtomicBool,
+) -> IoResult<()> {
+    // Set beforehand so that a concurrent wait cannot observe the exit
+    // before the flag
+    let was_terminated = terminated.swap(true, Ordering::SeqCst);
+    let res = match job {
+        Some(job) => unsafe { TerminateJobObject(job.as_raw_handle() as _, TERMINATED_EXIT_CODE) },
+        None => unsafe { TerminateProcess(proc.as_raw_handle() as _, TERMINATED_EXIT_CODE) },
+    };
+    if res == 0 {
+        terminated.store(was_terminated, Ordering::SeqCst);
+        Err(IoError::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn send_signal(
+    proc: IoResult<&OwnedHandle>,
+    job: Option<&OwnedHandle>,
+    terminated: &AtomicBool,
+    input: Option<&Weak<FileDescriptor>>,
+    signal: Signal,
+) -> IoResult<()> {
+    match signal {
+        // conpty turns ^C into CTRL_C_EVENT for the attached processes
+        Signal::Interrupt => match input {
+            Some(input) => match input.upgrade() {
+                Some(input) => input
+                    .try_clone()
+                    .map_err(clone_error(
+                        "pseudoconsole input pipe",
+                        "deliver an interrupt",
+                    ))?
+                    .write_all(b"\x03"),
+                None => Err(IoError::new(
+                    ErrorKind::BrokenPipe,
+                    "the pty has already been closed",
+                )),
+            },
+            None => Err(unsupported_signal(signal)),
+        },
+        Signal::Terminate | Signal::Kill => terminate(proc?, job, terminated),
+        Signal::Hangup | Signal::Quit => Err(unsupported_signal(signal)),
+    }
+}
+
+/// Returns the number of bytes that can be read from the pipe `fd`
+/// without blocking, or None if the write end has been closed and
+/// everything has been read, which is EOF
+pub(crate) fn pending_bytes(fd: &FileDescriptor) -> IoResult<Option<usize>> {
+    let mut avail: DWORD = 0;
+    let res = unsafe {
+        PeekNamedPipe(
+            fd.as_raw_handle() as _,
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+            &mut avail,
+            std::ptr::null_mut(),
+        )
+    };
+    if res == 0 {
+        let err = IoError::last_os_error();
+        if err.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) {
+            return Ok(None);
+        }
+        return Err(err);
+    }
+    Ok(Some(avail as usize))
+}
+
+/// Reads as much of the output that is pending in the pipe `fd` as
+/// will fit in `buf`, returning 0 rather than blocking if there is none
+fn read_available(fd: &mut FileDescriptor, buf: &mut [u8]) -> IoResult<usize> {
+    match pending_bytes(fd)? {
+        None | Some(0) => Ok(0),
+        Some(avail) => {
+            let len = buf.len().min(avail);
+            std::io::Read::read(fd, &mut buf[..len])
+        }
+    }
+}
+
+/// Retrieves the exit status of a process that is known to have exited.
+/// `terminated` is true if we terminated it.
+fn exit_status(proc: &OwnedHandle, terminated: bool) -> IoResult<ExitStatus> {
+    let mut status: DWORD = 0;
+    let res = unsafe { GetExitCodeProcess(proc.as_raw_handle() as _, &mut status) };
+    if res != 0 {
+        if terminated && status == TERMINATED_EXIT_CODE {
+            return Ok(ExitStatus::with_terminated_exit_code(status));
+        }
+        Ok(ExitStatus::with_exit_code(status))
+    } else {
+        Err(IoError::last_os_error())
+    }
+}
+
+/// Converts a failure to duplicate `handle` into an io error, for the
+/// methods of `Child` and `ChildKiller`
+fn clone_error(
+    handle: &'static str,
+    operation: &'static str,
+) -> impl FnOnce(filedescriptor::Error) -> IoError {
+    move |err| {
+        IoError::new(
+            ErrorKind::Other,
+            PtyError::handle_clone(handle, operation)(err),
+        )
+    }
 }
 
 impl WinChild {
+    /// Duplicates the process handle, so that it can be used without
+    /// holding the lock
+    fn clone_proc(&self, operation: &'static str) -> IoResult<OwnedHandle> {
+        self.proc
+            .lock()
+            .unwrap()
+            .try_clone()
+            .map_err(clone_error("child process", operation))
+    }
+
     fn is_complete(&mut self) -> IoResult<Option<ExitStatus>> {
-        let mut status: DWORD = 0;
-        let proc = self.proc.lock().unwrap().try_clone().unwrap();
-        let res = unsafe { GetExitCodeProcess(proc.as_raw_handle() as _, &mut status) };
-        if res != 0 {
-            if status == STILL_ACTIVE {
-                Ok(None)
-            } else {
-                Ok(Some(ExitStatus::with_exit_code(status)))
-            }
-        } else {
-            Ok(None)
+        let proc = self.clone_proc("query its status")?;
+        // The process handle is signalled when the process exits.
+        // We check that rather than comparing the exit code against
+        // STILL_ACTIVE, because a process can legitimately exit with
+        // that value.
+        match unsafe { WaitForSingleObject(proc.as_raw_handle() as _, 0) } {
+            WAIT_OBJECT_0 => exit_status(&proc, self.terminated.load(Ordering::SeqCst)).map(Some),
+            WAIT_TIMEOUT => Ok(None),
+            _ => Err(IoError::last_os_error()),
         }
     }
 
     fn do_kill(&mut self) -> IoResult<()> {
-        let proc = self.proc.lock().unwrap().try_clone().unwrap();
-        let res = unsafe { TerminateProcess(proc.as_raw_handle() as _, 1) };
-        let err = IoError::last_os_error();
-        if res != 0 {
-            Err(err)
-        } else {
-            Ok(())
-        }
+        let proc = self.clone_proc("terminate it")?;
+        terminate(&proc, self.job.as_ref(), &self.terminated)
     }
 }
 
@@ -57,30 +209,113 @@ impl ChildKiller for WinChild {
     }
 
     fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
-        let proc = self.proc.lock().unwrap().try_clone().unwrap();
-        Box::new(WinChildKiller { proc })
+        Box::new(WinChildKiller::new(
+            &self.proc.lock().unwrap(),
+            self.job.as_ref(),
+            self.input.clone(),
+            Arc::clone(&self.terminated),
+        ))
+    }
+
+    fn send_signal(&mut self, signal: Signal) -> IoResult<()> {
+        let proc = self.clone_proc("signal it")?;
+        send_signal(
+            Ok(&proc),
+            self.job.as_ref(),
+            &self.terminated,
+            self.input.as_ref(),
+            signal,
+        )
     }
 }
 
 #[derive(Debug)]
 pub struct WinChildKiller {
-    proc: OwnedHandle,
+    /// None if the handles couldn't be duplicated when this killer was
+    /// created, in which case it can only deliver interrupts
+    proc: Option<OwnedHandle>,
+    job: Option<OwnedHandle>,
+    input: Option<Weak<FileDescriptor>>,
+    terminated: Arc<AtomicBool>,
+}
+
+impl WinChildKiller {
+    /// `clone_killer` cannot report errors, so a failure to duplicate
+    /// the handles, typically because the process has run out of them,
+    /// is logged, and the killer fails to kill rather than panicking
+    fn new(
+        proc: &OwnedHandle,
+        job: Option<&OwnedHandle>,
+        input: Option<Weak<FileDescriptor>>,
+        terminated: Arc<AtomicBool>,
+    ) -> Self {
+        let handles = proc
+            .try_clone()
+            .map_err(PtyError::handle_clone("child process", "clone a killer"))
+            .and_then(|proc| {
+                let job = match job {
+                    Some(job) => Some(
+                        job.try_clone()
+                            .map_err(PtyError::handle_clone("job", "clone a killer"))?,
+                    ),
+                    None => None,
+                };
+                Ok((proc, job))
+            })
+            .map_err(|err| log::error!("{:#}", anyhow::Error::from(err)))
+            .ok();
+        let (proc, job) = match handles {
+            Some((proc, job)) => (Some(proc), job),
+            None => (None, None),
+        };
+        Self {
+            proc,
+            job,
+            input,
+            terminated,
+        }
+    }
+
+    fn proc(&self) -> IoResult<&OwnedHandle> {
+        self.proc.as_ref().ok_or_else(|| {
+            IoError::new(
+                ErrorKind::Other,
+                "failed to duplicate the child process handles in order to clone a killer",
+            )
+        })
+    }
 }
 
 impl ChildKiller for WinChildKiller {
     fn kill(&mut self) -> IoResult<()> {
-        let res = unsafe { TerminateProcess(self.proc.as_raw_handle() as _, 1) };
-        let err = IoError::last_os_error();
-        if res != 0 {
-            Err(err)
-        } else {
-            Ok(())
-        }
+        terminate(self.proc()?, self.job.as_ref(), &self.terminated)
     }
 
     fn clone_killer(&self) -> Box<dyn ChildKiller + Send + Sync> {
-        let proc = self.proc.try_clone().unwrap();
-        Box::new(WinChildKiller { proc })
+        match &self.proc {
+            Some(proc) => Box::new(WinChildKiller::new(
+                proc,
+                self.job.as_ref(),
+                self.input.clone(),
+                Arc::clone(&self.terminated),
+            )),
+            None => Box::new(WinChildKiller {
+                proc: None,
+                job: None,
+                input: self.input.clone(),
+                terminated: Arc::clone(&self.terminated),
+            }),
+        }
+    }
+
+    fn send_signal(&mut self, signal: Signal) -> IoResult<()> {
+        send_signal(
+            self.proc(),
+            self.job.as_ref(),
+            &self.terminated,
+            self.input.as_ref(),
+            signal,
+        )
     }
 }
 
@@ -93,26 +328,87 @@ impl Child for WinChild {
         if let Ok(Some(status)) = self.try_wait() {
             return Ok(status);
         }
-        let proc = self.proc.lock().unwrap().try_clone().unwrap();
-        unsafe {
-            WaitForSingleObject(proc.as_raw_handle() as _, INFINITE);
+        let proc = self.clone_proc("wait for it")?;
+        match unsafe { WaitForSingleObject(proc.as_raw_handle() as _, INFINITE) } {
+            WAIT_OBJECT_0 => exit_status(&proc, self.terminated.load(Ordering::SeqCst)),
+            _ => Err(IoError::last_os_error()),
         }
-        let mut status: DWORD = 0;
-        let res = unsafe { GetExitCodeProcess(proc.as_raw_handle() as _, &mut status) };
-        if res != 0 {
-            Ok(ExitStatus::with_exit_code(status))
-        } else {
-            Err(IoError::last_os_error())
+    }
+
+    fn wait_timeout(&mut self, timeout: Duration) -> IoResult<Option<ExitStatus>> {
+        // Durations that don't fit are clamped to INFINITE
+        let millis = u32::try_from(timeout.as_millis()).unwrap_or(INFINITE);
+        let proc = self.clone_proc("wait for it")?;
+        match unsafe { WaitForSingleObject(proc.as_raw_handle() as _, millis) } {
+            WAIT_OBJECT_0 => self.try_wait(),
+            WAIT_TIMEOUT => Ok(None),
+            _ => Err(IoError::last_os_error()),
         }
     }
 
-    fn process_id(&self) -> Option<u32> {
-        let res = unsafe { GetProcessId(self.proc.lock().unwrap().as_raw_handle() as _) };
-        if res == 0 {
-            None
-        } else {
-            Some(res)
+    fn resume(&mut self) -> IoResult<()> {
+        let thread = match (self.suspended, self.main_thread.as_ref()) {
+            (true, Some(thread)) => thread,
+            _ => {
+                return Err(IoError::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "the process was not started suspended, or was already resumed",
+                ))
+            }
+        };
+        if unsafe { ResumeThread(thread.as_raw_handle() as _) } == DWORD::MAX {
+            return Err(IoError::last_os_error());
         }
+        self.suspended = false;
+        Ok(())
+    }
+
+    fn main_thread_handle(&self) -> Option<RawHandle> {
+        self.main_thread
+            .as_ref()
+            .map(|thread| thread.as_raw_handle())
+    }
+
+    fn send_ctrl_break(&mut self) -> IoResult<()> {
+        if unsafe { GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, self.pid) } == 0 {
+            return Err(IoError::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn detach(mut self: Box<Self>) {
+        if let Some(job) = self.job.take() {
+            if let Err(err) = job::disable_kill_on_close(&job) {
+                log::error!("failed to detach child from its job: {:#}", err);
+                // Closing the job would terminate the process, so leak
+                // the handle instead
+                std::mem::forget(job);
+            }
+        }
+    }
+
+    fn take_stderr(&mut self) -> Option<Box<dyn std::io::Read + Send>> {
+        self.stderr
+            .take()
+            .map(|stderr| Box::new(stderr) as Box<dyn std::io::Read + Send>)
+    }
+
+    fn release_input(&mut self) {
+        self.input.take();
+    }
+
+    fn current_working_dir(&self) -> Option<std::path::PathBuf> {
+        let proc = self.proc.lock().unwrap();
+        current_working_dir(proc.as_raw_handle())
+    }
+
+    fn is_alive(&self) -> bool {
+        let proc = self.proc.lock().unwrap();
+        windows_handle_is_alive(proc.as_raw_handle())
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        Some(self.pid)
     }
 
     fn as_raw_handle(&self) -> Option<std::os::windows::io::RawHandle> {
@@ -132,7 +428,7 @@ impl std::future::Future for WinChild {
                 struct PassRawHandleToWaiterThread(pub RawHandle);
                 unsafe impl Send for PassRawHandleToWaiterThread {}
 
-                let proc = self.proc.lock().unwrap().try_clone()?;
+                let proc = self.clone_proc("wait for it")?;
                 let handle = PassRawHandleToWaiterThread(proc.as_raw_handle());
 
                 let waker = cx.waker().clone();
@@ -147,3 +443,124 @@ impl std::future::Future for WinChild {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::windows::io::FromRawHandle;
+
+    #[test]
+    fn try_wait_invalid_handle() {
+        let mut child = WinChild {
+            proc: Mutex::new(unsafe { OwnedHandle::from_raw_handle(std::ptr::null_mut()) }),
+            pid: 0,
+            job: None,
+            input: None,
+            stderr: None,
+            main_thread: None,
+            suspended: false,
+            terminated: Arc::new(AtomicBool::new(false)),
+        };
+        assert!(child.try_wait().is_err());
+    }
+
+    #[test]
+    fn handle_clone_error() {
+        // Not a valid handle, but not one of the values that
+        // try_clone passes through unchanged either
+        let mut child = WinChild {
+            proc: Mutex::new(unsafe { OwnedHandle::from_raw_handle(0x1234 as _) }),
+            pid: 0,
+            job: None,
+            input: None,
+            stderr: None,
+            main_thread: None,
+            suspended: false,
+            terminated: Arc::new(AtomicBool::new(false)),
+        };
+        let err = child.wait().unwrap_err();
+        match err.get_ref().and_then(|err| err.downcast_ref::<PtyError>()) {
+            Some(PtyError::HandleClone {
+                handle, operation, ..
+            }) => {
+                assert_eq!(*handle, "child process");
+                assert_eq!(*operation, "wait for it");
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+        // Don't try to close the bogus handle
+        std::mem::forget(child);
+    }
+
+    #[test]
+    fn exit_code_259_is_not_still_active() {
+        use std::os::windows::io::IntoRawHandle;
+        // 259 is the value of STILL_ACTIVE
+        let proc = std::process::Command::new("cmd.exe")
+            .args(&["/c", "exit 259"])
+            .spawn()
+            .unwrap();
+        let pid = proc.id();
+        let mut child = WinChild {
+            proc: Mutex::new(unsafe { OwnedHandle::from_raw_handle(proc.into_raw_handle()) }),
+            pid,
+            job: None,
+            input: None,
+            stderr: None,
+            main_thread: None,
+            suspended: false,
+            terminated: Arc::new(AtomicBool::new(false)),
+        };
+        assert_eq!(child.wait().unwrap().exit_code(), 259);
+        assert!(!child.is_alive());
+        assert_eq!(child.try_wait().unwrap().unwrap().exit_code(), 259);
+    }
+
+    #[test]
+    fn pid_survives_handle_duplication() {
+        use std::os::windows::io::IntoRawHandle;
+        use winapi::um::handleapi::DuplicateHandle;
+        use winapi::um::winnt::DUPLICATE_SAME_ACCESS;
+
+        let proc = std::process::Command::new("ping.exe")
+            .args(&["-n", "60", "127.0.0.1"])
+            .spawn()
+            .unwrap();
+        let pid = proc.id();
+        let mut child = WinChild {
+            proc: Mutex::new(unsafe { OwnedHandle::from_raw_handle(proc.into_raw_handle()) }),
+            pid,
+            job: None,
+            input: None,
+            stderr: None,
+            main_thread: None,
+            suspended: false,
+            terminated: Arc::new(AtomicBool::new(false)),
+        };
+
+        // Duplicate the handle as a server would for a client process;
+        // here the target is ourselves
+        let mut dup = std::ptr::null_mut();
+        let res = unsafe {
+            DuplicateHandle(
+                GetCurrentProcess(),
+                child.as_raw_handle().unwrap() as _,
+                GetCurrentProcess(),
+                &mut dup,
+                0,
+                0,
+                DUPLICATE_SAME_ACCESS,
+            )
+        };
+        assert_ne!(res, 0);
+        let dup = unsafe { OwnedHandle::from_raw_handle(dup as _) };
+        assert_eq!(unsafe { GetProcessId(dup.as_raw_handle() as _) }, pid);
+        assert_eq!(child.process_id(), Some(pid));
+
+        let mut killer = child.clone_killer();
+        drop(dup);
+        killer.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(child.process_id(), Some(pid));
+    }
+}
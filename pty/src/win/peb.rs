@@ -0,0 +1,114 @@
+//! Reads the current directory of another process from its process
+//! environment block (PEB).  Windows has no API for this, so we locate
+//! the `RTL_USER_PROCESS_PARAMETERS` of the process and copy the path
+//! out of its address space.
+use ntapi::ntpebteb::PEB;
+use ntapi::ntpsapi::{
+    NtQueryInformationProcess, ProcessBasicInformation, ProcessWow64Information,
+    PROCESS_BASIC_INFORMATION,
+};
+use ntapi::ntrtl::RTL_USER_PROCESS_PARAMETERS;
+use ntapi::ntwow64::{PEB32, RTL_USER_PROCESS_PARAMETERS32};
+use std::ffi::OsString;
+use std::mem::MaybeUninit;
+use std::os::windows::ffi::OsStringExt;
+use std::os::windows::io::RawHandle;
+use std::path::PathBuf;
+use winapi::shared::minwindef::LPVOID;
+use winapi::shared::ntdef::NT_SUCCESS;
+use winapi::um::memoryapi::ReadProcessMemory;
+use winapi::um::processthreadsapi::GetCurrentProcess;
+
+/// Paths longer than this are assumed to be the result of reading
+/// from the wrong address
+const MAX_PATH_BYTES: usize = 32768 * 2;
+
+/// Returns the current directory of the process `proc`, which must have
+/// `PROCESS_QUERY_INFORMATION` and `PROCESS_VM_READ` access.
+/// The handles returned by `CreateProcessW` have full access.
+/// Returns None for a 64-bit process if we are a 32-bit process, as
+/// its PEB is then out of reach.
+pub(crate) fn current_working_dir(proc: RawHandle) -> Option<PathBuf> {
+    // A 32-bit process on 64-bit windows has a separate 32-bit PEB,
+    // which is the one that it keeps up to date
+    let peb32: LPVOID = query_proc(proc, ProcessWow64Information)?;
+    let (buffer, len) = if !peb32.is_null() {
+        let peb: PEB32 = read_struct(proc, peb32)?;
+        let params: RTL_USER_PROCESS_PARAMETERS32 =
+            read_struct(proc, peb.ProcessParameters as usize as _)?;
+        let path = params.CurrentDirectory.DosPath;
+        (path.Buffer as usize as LPVOID, path.Length as usize)
+    } else {
+        let we_are_wow64: LPVOID =
+            query_proc(unsafe { GetCurrentProcess() } as _, ProcessWow64Information)?;
+        if !we_are_wow64.is_null() {
+            return None;
+        }
+        let info: PROCESS_BASIC_INFORMATION = query_proc(proc, ProcessBasicInformation)?;
+        let peb: PEB = read_struct(proc, info.PebBaseAddress as _)?;
+        let params: RTL_USER_PROCESS_PARAMETERS = read_struct(proc, peb.ProcessParameters as _)?;
+        let path = params.CurrentDirectory.DosPath;
+        (path.Buffer as LPVOID, path.Length as usize)
+    };
+    if len > MAX_PATH_BYTES {
+        return None;
+    }
+
+    let mut wide = vec![0u16; len / 2];
+    let mut bytes_read = 0;
+    let res = unsafe {
+        ReadProcessMemory(
+            proc as _,
+            buffer as _,
+            wide.as_mut_ptr() as _,
+            len,
+            &mut bytes_read,
+        )
+    };
+    if res == 0 {
+        return None;
+    }
+    wide.truncate(bytes_read / 2);
+    // The current directory always has a trailing backslash, which we
+    // only keep for the root of a drive, such as `C:\`
+    if wide.len() > 3 && wide.last() == Some(&(b'\\' as u16)) {
+        wide.pop();
+    }
+    Some(OsString::from_wide(&wide).into())
+}
+
+/// Fetches the information class `what` as a `T`
+fn query_proc<T>(proc: RawHandle, what: u32) -> Option<T> {
+    let mut data = MaybeUninit::<T>::uninit();
+    let res = unsafe {
+        NtQueryInformationProcess(
+            proc as _,
+            what,
+            data.as_mut_ptr() as _,
+            std::mem::size_of::<T>() as _,
+            std::ptr::null_mut(),
+        )
+    };
+    if !NT_SUCCESS(res) {
+        return None;
+    }
+    Some(unsafe { data.assume_init() })
+}
+
+/// Reads a `T` from the address space of the process
+fn read_struct<T>(proc: RawHandle, addr: LPVOID) -> Option<T> {
+    let mut data = MaybeUninit::<T>::uninit();
+    let res = unsafe {
+        ReadProcessMemory(
+            proc as _,
+            addr as _,
+            data.as_mut_ptr() as _,
+            std::mem::size_of::<T>(),
+            std::ptr::null_mut(),
+        )
+    };
+    if res == 0 {
+        return None;
+    }
+    Some(unsafe { data.assume_init() })
+}
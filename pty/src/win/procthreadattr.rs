@@ -4,11 +4,18 @@ use std::io::Error as IoError;
 use std::{mem, ptr};
 use winapi::shared::minwindef::DWORD;
 use winapi::um::processthreadsapi::*;
+use winapi::um::winnt::{HANDLE, PVOID};
 
 const PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE: usize = 0x00020016;
+const PROC_THREAD_ATTRIBUTE_HANDLE_LIST: usize = 0x00020002;
+const PROC_THREAD_ATTRIBUTE_PARENT_PROCESS: usize = 0x00020000;
 
 pub struct ProcThreadAttributeList {
     data: Vec<u8>,
+    /// The attribute list references this memory rather than
+    /// copying it, so we need to keep it alive
+    handles: Vec<HANDLE>,
+    parent: Box<HANDLE>,
 }
 
 impl ProcThreadAttributeList {
@@ -22,11 +29,10 @@ impl ProcThreadAttributeList {
                 &mut bytes_required,
             )
         };
-        let mut data = Vec::with_capacity(bytes_required);
-        // We have the right capacity, so force the vec to consider itself
-        // that length.  The contents of those bytes will be maintained
-        // by the win32 apis used in this impl.
-        unsafe { data.set_len(bytes_required) };
+        // The contents of these bytes are maintained by the win32 apis
+        // used in this impl, but we zero-fill them rather than exposing
+        // uninitialized memory via the Vec.
+        let mut data = vec![0u8; bytes_required];
 
         let attr_ptr = data.as_mut_slice().as_mut_ptr() as *mut _;
         let res = unsafe {
@@ -37,21 +43,27 @@ impl ProcThreadAttributeList {
             "InitializeProcThreadAttributeList failed: {}",
             IoError::last_os_error()
         );
-        Ok(Self { data })
+        Ok(Self {
+            data,
+            handles: vec![],
+            parent: Box::new(ptr::null_mut()),
+        })
     }
 
     pub fn as_mut_ptr(&mut self) -> LPPROC_THREAD_ATTRIBUTE_LIST {
         self.data.as_mut_slice().as_mut_ptr() as *mut _
     }
 
-    pub fn set_pty(&mut self, con: HPCON) -> Result<(), Error> {
+    /// Sets an attribute.  `value` must remain valid until the list
+    /// is dropped.
+    fn update(&mut self, attribute: usize, value: PVOID, size: usize) -> Result<(), Error> {
         let res = unsafe {
             UpdateProcThreadAttribute(
                 self.as_mut_ptr(),
                 0,
-                PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE,
-                con,
-                mem::size_of::<HPCON>(),
+                attribute,
+                value,
+                size,
                 ptr::null_mut(),
                 ptr::null_mut(),
             )
@@ -63,6 +75,35 @@ impl ProcThreadAttributeList {
         );
         Ok(())
     }
+
+    pub fn set_pty(&mut self, con: HPCON) -> Result<(), Error> {
+        self.update(
+            PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE,
+            con,
+            mem::size_of::<HPCON>(),
+        )
+    }
+
+    /// Restricts the set of handles inherited by the child to `handles`
+    pub fn set_handle_list(&mut self, handles: Vec<HANDLE>) -> Result<(), Error> {
+        self.handles = handles;
+        let value = self.handles.as_mut_ptr() as PVOID;
+        let size = self.handles.len() * mem::size_of::<HANDLE>();
+        self.update(PROC_THREAD_ATTRIBUTE_HANDLE_LIST, value, size)
+    }
+
+    /// Causes the child to be created as though it were spawned by
+    /// the process identified by `parent`, which must have been opened
+    /// with `PROCESS_CREATE_PROCESS` access
+    pub fn set_parent_process(&mut self, parent: HANDLE) -> Result<(), Error> {
+        *self.parent = parent;
+        let value = &mut *self.parent as *mut HANDLE as PVOID;
+        self.update(
+            PROC_THREAD_ATTRIBUTE_PARENT_PROCESS,
+            value,
+            mem::size_of::<HANDLE>(),
+        )
+    }
 }
 
 impl Drop for ProcThreadAttributeList {
@@ -70,3 +111,23 @@ impl Drop for ProcThreadAttributeList {
         unsafe { DeleteProcThreadAttributeList(self.as_mut_ptr()) };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_capacity_one() {
+        let mut attrs = ProcThreadAttributeList::with_capacity(1).unwrap();
+        assert!(!attrs.as_mut_ptr().is_null());
+    }
+
+    #[test]
+    fn parent_process() {
+        let mut attrs = ProcThreadAttributeList::with_capacity(1).unwrap();
+        attrs
+            .set_parent_process(unsafe { GetCurrentProcess() })
+            .unwrap();
+        assert!(!attrs.as_mut_ptr().is_null());
+    }
+}
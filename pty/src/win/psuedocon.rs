@@ -1,29 +1,45 @@
 use super::WinChild;
-use crate::cmdbuilder::CommandBuilder;
+use crate::cmdbuilder::{CommandBuilder, StdioMode};
+use crate::win::job::create_kill_on_close_job;
 use crate::win::procthreadattr::ProcThreadAttributeList;
-use anyhow::{bail, ensure, Error};
-use filedescriptor::{FileDescriptor, OwnedHandle};
+use crate::{Child, ChildKiller, PtyError};
+use anyhow::{anyhow, bail, ensure, Error};
+use filedescriptor::{FileDescriptor, OwnedHandle, Pipe};
 use lazy_static::lazy_static;
 use shared_library::shared_library;
-use std::ffi::OsString;
+use std::ffi::OsStr;
 use std::io::Error as IoError;
-use std::os::windows::ffi::OsStringExt;
+use std::os::windows::ffi::OsStrExt;
 use std::os::windows::io::{AsRawHandle, FromRawHandle};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::time::Duration;
 use std::{mem, ptr};
-use winapi::shared::minwindef::DWORD;
-use winapi::shared::winerror::{HRESULT, S_OK};
+use winapi::shared::minwindef::{BOOL, DWORD, TRUE};
+use winapi::shared::winerror::{
+    ERROR_BROKEN_PIPE, ERROR_INVALID_HANDLE, ERROR_NO_DATA, ERROR_PIPE_NOT_CONNECTED, HRESULT,
+    HRESULT_FROM_WIN32, S_OK,
+};
+use winapi::um::fileapi::{CreateFileW, OPEN_EXISTING};
 use winapi::um::handleapi::*;
+use winapi::um::jobapi2::TerminateJobObject;
+use winapi::um::minwinbase::SECURITY_ATTRIBUTES;
+use winapi::um::processenv::GetStdHandle;
 use winapi::um::processthreadsapi::*;
 use winapi::um::winbase::{
-    CREATE_UNICODE_ENVIRONMENT, EXTENDED_STARTUPINFO_PRESENT, STARTF_USESTDHANDLES, STARTUPINFOEXW,
+    FormatMessageW, CREATE_NEW_PROCESS_GROUP, CREATE_SUSPENDED, CREATE_UNICODE_ENVIRONMENT,
+    EXTENDED_STARTUPINFO_PRESENT, FORMAT_MESSAGE_FROM_SYSTEM, FORMAT_MESSAGE_IGNORE_INSERTS,
+    HANDLE_FLAG_INHERIT, STARTF_USESTDHANDLES, STARTUPINFOEXW, STD_ERROR_HANDLE, STD_INPUT_HANDLE,
+    STD_OUTPUT_HANDLE,
 };
 use winapi::um::wincon::COORD;
-use winapi::um::winnt::HANDLE;
+use winapi::um::winnt::{
+    DUPLICATE_SAME_ACCESS, FILE_SHARE_READ, FILE_SHARE_WRITE, GENERIC_READ, GENERIC_WRITE, HANDLE,
+};
 
 pub type HPCON = HANDLE;
 
+pub const PSEUDOCONSOLE_INHERIT_CURSOR: DWORD = 0x1;
 pub const PSEUDOCONSOLE_RESIZE_QUIRK: DWORD = 0x2;
 pub const PSEUDOCONSOLE_WIN32_INPUT_MODE: DWORD = 0x4;
 
@@ -39,25 +55,240 @@ shared_library!(ConPtyFuncs,
     pub fn ClosePseudoConsole(hpc: HPCON),
 );
 
-fn load_conpty() -> ConPtyFuncs {
+/// Tracks an explicitly configured conpty implementation, and whether
+/// the functions have already been loaded
+#[derive(Default)]
+struct ConPtyOverride {
+    path: Option<PathBuf>,
+    loaded: bool,
+}
+
+fn load_conpty() -> Option<ConPtyFuncs> {
+    let mut conpty_override = CONPTY_OVERRIDE.lock().unwrap();
+    conpty_override.loaded = true;
+
+    if let Some(path) = &conpty_override.path {
+        match ConPtyFuncs::open(path) {
+            Ok(funcs) => return Some(funcs),
+            Err(err) => log::error!(
+                "failed to load conpty from {}, falling back to the system conpty: {:?}",
+                path.display(),
+                err
+            ),
+        }
+    }
+
     // If the kernel doesn't export these functions then their system is
     // too old and we cannot run.
-    let kernel = ConPtyFuncs::open(Path::new("kernel32.dll")).expect(
-        "this system does not support conpty.  Windows 10 October 2018 or newer is required",
-    );
+    let kernel = match ConPtyFuncs::open(Path::new("kernel32.dll")) {
+        Ok(kernel) => kernel,
+        Err(err) => {
+            log::error!(
+                "this system does not support conpty.  \
+                 Windows 10 October 2018 or newer is required: {:?}",
+                err
+            );
+            return None;
+        }
+    };
 
     // We prefer to use a sideloaded conpty.dll and openconsole.exe host deployed
     // alongside the application.  We check for this after checking for kernel
     // support so that we don't try to proceed and do something crazy.
     if let Ok(sideloaded) = ConPtyFuncs::open(Path::new("conpty.dll")) {
-        sideloaded
+        Some(sideloaded)
     } else {
-        kernel
+        Some(kernel)
     }
 }
 
+/// How long to wait for `chcp.com` to set the code page of the console
+const CHCP_TIMEOUT: Duration = Duration::from_secs(10);
+
 lazy_static! {
-    static ref CONPTY: ConPtyFuncs = load_conpty();
+    static ref CONPTY_OVERRIDE: Mutex<ConPtyOverride> = Mutex::new(ConPtyOverride::default());
+    static ref CONPTY: Option<ConPtyFuncs> = load_conpty();
+}
+
+/// Arrange for the conpty functions to be loaded from the DLL at `path`,
+/// rather than from a `conpty.dll` deployed alongside the application
+/// or from `kernel32.dll`.  This allows using a redistributable conpty
+/// implementation, or a mock for testing purposes.
+/// This must be called before the first pseudoconsole is created;
+/// an error is returned if the functions have already been loaded,
+/// or if the DLL doesn't export the conpty functions.
+pub fn load_conpty_from(path: &Path) -> Result<(), Error> {
+    ConPtyFuncs::open(path).map_err(|err| {
+        anyhow!(
+            "{} does not provide the conpty functions: {:?}",
+            path.display(),
+            err
+        )
+    })?;
+
+    let mut conpty_override = CONPTY_OVERRIDE.lock().unwrap();
+    ensure!(
+        !conpty_override.loaded,
+        "conpty has already been loaded; load_conpty_from must be called \
+         before the first pseudoconsole is created"
+    );
+    conpty_override.path = Some(path.to_path_buf());
+    Ok(())
+}
+
+fn conpty() -> Result<&'static ConPtyFuncs, PtyError> {
+    CONPTY.as_ref().ok_or(PtyError::ConPtyUnavailable)
+}
+
+/// Returns true if the ConPTY functions are available on this system.
+/// This can be used to probe for support before calling `openpty`
+/// and to select an alternative implementation when it returns false.
+pub fn conpty_is_available() -> bool {
+    CONPTY.is_some()
+}
+
+/// Returns a human readable description of an HRESULT, falling back
+/// to just the numeric value if the system has no message for it.
+fn hresult_to_string(result: HRESULT) -> String {
+    let mut buf = [0u16; 512];
+    let len = unsafe {
+        FormatMessageW(
+            FORMAT_MESSAGE_FROM_SYSTEM | FORMAT_MESSAGE_IGNORE_INSERTS,
+            ptr::null(),
+            result as DWORD,
+            0,
+            buf.as_mut_ptr(),
+            buf.len() as DWORD,
+            ptr::null_mut(),
+        )
+    };
+    if len == 0 {
+        format!("HRESULT 0x{:08x}", result)
+    } else {
+        let message = String::from_utf16_lossy(&buf[..len as usize]);
+        format!("{} (HRESULT 0x{:08x})", message.trim_end(), result)
+    }
+}
+
+/// Returns an inheritable duplicate of `handle`
+fn inheritable_handle(handle: HANDLE) -> Result<OwnedHandle, Error> {
+    let mut dup = ptr::null_mut();
+    let res = unsafe {
+        DuplicateHandle(
+            GetCurrentProcess(),
+            handle,
+            GetCurrentProcess(),
+            &mut dup,
+            0,
+            TRUE,
+            DUPLICATE_SAME_ACCESS,
+        )
+    };
+    ensure!(
+        res != 0,
+        "failed to duplicate handle: {}",
+        IoError::last_os_error()
+    );
+    Ok(unsafe { OwnedHandle::from_raw_handle(dup as _) })
+}
+
+/// Converts a possibly nul terminated wide string, as passed to
+/// `CreateProcessW`, into a String for use in messages.  Converting
+/// directly from UTF-16 preserves characters that are outside of the
+/// current code page; unpaired surrogates become U+FFFD.
+fn wide_to_string(wide: &[u16]) -> String {
+    let wide = match wide.split_last() {
+        Some((0, rest)) => rest,
+        _ => wide,
+    };
+    String::from_utf16_lossy(wide)
+}
+
+/// Marks handles that were passed to `CommandBuilder::inherit_handle`
+/// as inheritable, restoring their original flag when dropped
+struct MarkInheritable(Vec<(HANDLE, DWORD)>);
+
+impl MarkInheritable {
+    fn new(handles: &[usize]) -> Result<Self, Error> {
+        let mut marked = Self(vec![]);
+        for &handle in handles {
+            let handle = handle as HANDLE;
+            let mut flags: DWORD = 0;
+            ensure!(
+                unsafe { GetHandleInformation(handle, &mut flags) } != 0,
+                "invalid handle {:?} passed to CommandBuilder::inherit_handle: {}",
+                handle,
+                IoError::last_os_error()
+            );
+            ensure!(
+                unsafe { SetHandleInformation(handle, HANDLE_FLAG_INHERIT, HANDLE_FLAG_INHERIT) }
+                    != 0,
+                "failed to make handle {:?} inheritable: {}",
+                handle,
+                IoError::last_os_error()
+            );
+            marked.0.push((handle, flags));
+        }
+        Ok(marked)
+    }
+}
+
+impl Drop for MarkInheritable {
+    fn drop(&mut self) {
+        for (handle, flags) in &self.0 {
+            unsafe {
+                SetHandleInformation(*handle, HANDLE_FLAG_INHERIT, *flags & HANDLE_FLAG_INHERIT)
+            };
+        }
+    }
+}
+
+/// Returns an inheritable handle for a standard stream that is
+/// redirected away from the pty, or None if it should use the pty.
+/// `access` is used when opening the null device.
+/// `StdioMode::Pipe` is handled by the caller.
+fn stdio_handle(
+    mode: StdioMode,
+    std_handle: DWORD,
+    access: DWORD,
+) -> Result<Option<OwnedHandle>, Error> {
+    match mode {
+        StdioMode::Pty => return Ok(None),
+        StdioMode::Pipe => bail!("StdioMode::Pipe is only supported for stderr"),
+        StdioMode::Inherit => {
+            let parent = unsafe { GetStdHandle(std_handle) };
+            // GUI processes may not have standard handles, in which
+            // case we fall back to the null device
+            if !parent.is_null() && parent != INVALID_HANDLE_VALUE {
+                return inheritable_handle(parent).map(Some);
+            }
+        }
+        StdioMode::Null => {}
+    }
+
+    let mut sa = SECURITY_ATTRIBUTES {
+        nLength: mem::size_of::<SECURITY_ATTRIBUTES>() as DWORD,
+        lpSecurityDescriptor: ptr::null_mut(),
+        bInheritHandle: TRUE,
+    };
+    let name: Vec<u16> = OsStr::new("NUL").encode_wide().chain(Some(0)).collect();
+    let handle = unsafe {
+        CreateFileW(
+            name.as_ptr(),
+            access,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            &mut sa,
+            OPEN_EXISTING,
+            0,
+            ptr::null_mut(),
+        )
+    };
+    ensure!(
+        handle != INVALID_HANDLE_VALUE,
+        "failed to open the null device: {}",
+        IoError::last_os_error()
+    );
+    Ok(Some(unsafe { OwnedHandle::from_raw_handle(handle as _) }))
 }
 
 pub struct PsuedoCon {
@@ -69,43 +300,76 @@ unsafe impl Sync for PsuedoCon {}
 
 impl Drop for PsuedoCon {
     fn drop(&mut self) {
-        unsafe { (CONPTY.ClosePseudoConsole)(self.con) };
+        if let Some(conpty) = CONPTY.as_ref() {
+            unsafe { (conpty.ClosePseudoConsole)(self.con) };
+        }
     }
 }
 
 impl PsuedoCon {
-    pub fn new(size: COORD, input: FileDescriptor, output: FileDescriptor) -> Result<Self, Error> {
+    pub fn new(
+        size: COORD,
+        input: FileDescriptor,
+        output: FileDescriptor,
+        flags: DWORD,
+    ) -> Result<Self, Error> {
+        let conpty = conpty()?;
         let mut con: HPCON = INVALID_HANDLE_VALUE;
         let result = unsafe {
-            (CONPTY.CreatePseudoConsole)(
+            (conpty.CreatePseudoConsole)(
                 size,
                 input.as_raw_handle() as _,
                 output.as_raw_handle() as _,
-                PSEUDOCONSOLE_RESIZE_QUIRK | PSEUDOCONSOLE_WIN32_INPUT_MODE,
+                flags,
                 &mut con,
             )
         };
         ensure!(
             result == S_OK,
-            "failed to create psuedo console: HRESULT {}",
-            result
+            "failed to create psuedo console: {}",
+            hresult_to_string(result)
         );
         Ok(Self { con })
     }
 
-    pub fn resize(&self, size: COORD) -> Result<(), Error> {
-        let result = unsafe { (CONPTY.ResizePseudoConsole)(self.con, size) };
-        ensure!(
-            result == S_OK,
-            "failed to resize console to {}x{}: HRESULT: {}",
-            size.X,
-            size.Y,
-            result
-        );
+    /// Returns true if `err`, as returned from `resize`, indicates that
+    /// the pseudoconsole has already gone away, so that there is nothing
+    /// left to resize
+    pub fn is_closed_error(err: &IoError) -> bool {
+        const CLOSED: &[DWORD] = &[
+            ERROR_BROKEN_PIPE,
+            ERROR_NO_DATA,
+            ERROR_PIPE_NOT_CONNECTED,
+            ERROR_INVALID_HANDLE,
+        ];
+        match err.raw_os_error() {
+            Some(code) => CLOSED
+                .iter()
+                .any(|&closed| HRESULT_FROM_WIN32(closed) == code),
+            None => false,
+        }
+    }
+
+    pub fn resize(&self, size: COORD) -> Result<(), IoError> {
+        let conpty = conpty().map_err(|err| IoError::new(std::io::ErrorKind::Unsupported, err))?;
+        let result = unsafe { (conpty.ResizePseudoConsole)(self.con, size) };
+        if result != S_OK {
+            // The system message table understands HRESULT values
+            return Err(IoError::from_raw_os_error(result));
+        }
         Ok(())
     }
 
     pub fn spawn_command(&self, cmd: CommandBuilder) -> anyhow::Result<WinChild> {
+        // Validate the command before running anything in the console
+        let (mut exe, mut cmdline) = cmd.cmdline()?;
+        let cmd_str = wide_to_string(&cmdline);
+
+        let cwd = cmd.current_directory()?;
+
+        if let Some(code_page) = cmd.code_page {
+            self.set_code_page(code_page)?;
+        }
         let mut si: STARTUPINFOEXW = unsafe { mem::zeroed() };
         si.StartupInfo.cb = mem::size_of::<STARTUPINFOEXW>() as u32;
         // Explicitly set the stdio handles as invalid handles otherwise
@@ -114,57 +378,211 @@ impl PsuedoCon {
         // For example, when daemonizing wezterm-mux-server, the stdio handles
         // are redirected to a log file and the spawned process would end up
         // writing its output there instead of to the pty we just created.
+        // Streams that are redirected away from the pty are given real
+        // handles here; the child inherits just those handles.
+        let stdin = stdio_handle(cmd.stdin, STD_INPUT_HANDLE, GENERIC_READ)?;
+        let stdout = stdio_handle(cmd.stdout, STD_OUTPUT_HANDLE, GENERIC_WRITE)?;
+        let (stderr, stderr_reader) = if cmd.stderr == StdioMode::Pipe {
+            let pipe = Pipe::new().map_err(PtyError::pipe)?;
+            // The child gets an inheritable duplicate of the write end;
+            // the original is closed when `pipe.write` is dropped here so
+            // that the reader sees EOF once the child is done with it
+            let write = inheritable_handle(pipe.write.as_raw_handle() as _)?;
+            (Some(write), Some(pipe.read))
+        } else {
+            let stderr = stdio_handle(cmd.stderr, STD_ERROR_HANDLE, GENERIC_WRITE)?;
+            (stderr, None)
+        };
+        let raw_handle = |handle: &Option<OwnedHandle>| {
+            handle
+                .as_ref()
+                .map(|h| h.as_raw_handle() as HANDLE)
+                .unwrap_or(INVALID_HANDLE_VALUE)
+        };
         si.StartupInfo.dwFlags = STARTF_USESTDHANDLES;
-        si.StartupInfo.hStdInput = INVALID_HANDLE_VALUE;
-        si.StartupInfo.hStdOutput = INVALID_HANDLE_VALUE;
-        si.StartupInfo.hStdError = INVALID_HANDLE_VALUE;
+        si.StartupInfo.hStdInput = raw_handle(&stdin);
+        si.StartupInfo.hStdOutput = raw_handle(&stdout);
+        si.StartupInfo.hStdError = raw_handle(&stderr);
 
-        let mut attrs = ProcThreadAttributeList::with_capacity(1)?;
+        let mut inherit: Vec<HANDLE> = [&stdin, &stdout, &stderr]
+            .iter()
+            .filter_map(|h| h.as_ref().map(|h| h.as_raw_handle() as HANDLE))
+            .collect();
+        // Kept alive until the process has been created
+        let _marked = MarkInheritable::new(&cmd.inherit_handles)?;
+        inherit.extend(cmd.inherit_handles.iter().map(|&h| h as HANDLE));
+        let inherit_handles = !inherit.is_empty();
+        ensure!(
+            !(inherit_handles && cmd.parent_process.is_some()),
+            "stdio redirection and inherited handles cannot be combined \
+             with CommandBuilder::parent_process"
+        );
+
+        let num_attributes = 1 + inherit_handles as u32 + cmd.parent_process.is_some() as u32;
+        let mut attrs = ProcThreadAttributeList::with_capacity(num_attributes)?;
         attrs.set_pty(self.con)?;
+        if inherit_handles {
+            attrs.set_handle_list(inherit)?;
+        }
+        if let Some(parent) = cmd.parent_process {
+            attrs.set_parent_process(parent as HANDLE)?;
+        }
         si.lpAttributeList = attrs.as_mut_ptr();
 
         let mut pi: PROCESS_INFORMATION = unsafe { mem::zeroed() };
 
-        let (mut exe, mut cmdline) = cmd.cmdline()?;
-        let cmd_os = OsString::from_wide(&cmdline);
-
-        let cwd = cmd.current_directory();
-
-        let res = unsafe {
-            CreateProcessW(
-                exe.as_mut_slice().as_mut_ptr(),
-                cmdline.as_mut_slice().as_mut_ptr(),
-                ptr::null_mut(),
-                ptr::null_mut(),
-                0,
-                EXTENDED_STARTUPINFO_PRESENT | CREATE_UNICODE_ENVIRONMENT,
-                cmd.environment_block().as_mut_slice().as_mut_ptr() as *mut _,
-                cwd.as_ref()
-                    .map(|c| c.as_slice().as_ptr())
-                    .unwrap_or(ptr::null()),
-                &mut si.StartupInfo,
-                &mut pi,
-            )
+        let (inherit_env, env_set) = cmd.env_summary();
+        log::debug!(
+            "spawning program={:?} cmdline={:?} cwd={:?} inherit_env={} env_set={:?}",
+            wide_to_string(&exe),
+            cmd_str,
+            cwd.as_ref().map(|c| wide_to_string(c)),
+            inherit_env,
+            env_set
+        );
+
+        let mut flags = EXTENDED_STARTUPINFO_PRESENT | CREATE_UNICODE_ENVIRONMENT;
+        if cmd.kill_on_drop_tree || cmd.suspended {
+            // When using a job, start suspended so that the child cannot
+            // start any processes of its own before it has been assigned
+            // to the job
+            flags |= CREATE_SUSPENDED;
+        }
+        if cmd.new_process_group {
+            flags |= CREATE_NEW_PROCESS_GROUP;
+        }
+        if let Some(priority) = cmd.priority {
+            flags |= priority.creation_flag();
+        }
+
+        let mut env = cmd.environment_block();
+        let cwd_ptr = cwd
+            .as_ref()
+            .map(|c| c.as_slice().as_ptr())
+            .unwrap_or(ptr::null());
+        let (res, func) = match cmd.token {
+            Some(token) => (
+                unsafe {
+                    CreateProcessAsUserW(
+                        token as HANDLE,
+                        exe.as_mut_slice().as_mut_ptr(),
+                        cmdline.as_mut_slice().as_mut_ptr(),
+                        ptr::null_mut(),
+                        ptr::null_mut(),
+                        inherit_handles as BOOL,
+                        flags,
+                        env.as_mut_slice().as_mut_ptr() as *mut _,
+                        cwd_ptr,
+                        &mut si.StartupInfo,
+                        &mut pi,
+                    )
+                },
+                "CreateProcessAsUserW",
+            ),
+            None => (
+                unsafe {
+                    CreateProcessW(
+                        exe.as_mut_slice().as_mut_ptr(),
+                        cmdline.as_mut_slice().as_mut_ptr(),
+                        ptr::null_mut(),
+                        ptr::null_mut(),
+                        inherit_handles as BOOL,
+                        flags,
+                        env.as_mut_slice().as_mut_ptr() as *mut _,
+                        cwd_ptr,
+                        &mut si.StartupInfo,
+                        &mut pi,
+                    )
+                },
+                "CreateProcessW",
+            ),
         };
         if res == 0 {
             let err = IoError::last_os_error();
-            let msg = format!(
-                "CreateProcessW `{:?}` in cwd `{:?}` failed: {}",
-                cmd_os,
-                cwd.as_ref().map(|c| OsString::from_wide(c)),
+            log::error!(
+                "{} `{}` in cwd `{}` failed: {}",
+                func,
+                cmd_str,
+                cwd.as_ref().map(|c| wide_to_string(c)).unwrap_or_default(),
                 err
             );
-            log::error!("{}", msg);
-            bail!("{}", msg);
+            return Err(PtyError::Spawn {
+                command: cmd_str,
+                source: err,
+            }
+            .into());
         }
 
+        log::debug!("spawned `{}` as pid {}", cmd_str, pi.dwProcessId);
+
         // Make sure we close out the thread handle so we don't leak it;
-        // we do this simply by making it owned
-        let _main_thread = unsafe { OwnedHandle::from_raw_handle(pi.hThread as _) };
+        // we do this simply by making it owned.  It is retained by the
+        // Child if the process is suspended or the caller asked for it
+        let main_thread = unsafe { OwnedHandle::from_raw_handle(pi.hThread as _) };
         let proc = unsafe { OwnedHandle::from_raw_handle(pi.hProcess as _) };
 
+        let job = if cmd.kill_on_drop_tree {
+            let job = match create_kill_on_close_job(&proc) {
+                Ok(job) => job,
+                Err(err) => {
+                    unsafe { TerminateProcess(proc.as_raw_handle() as _, 1) };
+                    return Err(err);
+                }
+            };
+            if !cmd.suspended
+                && unsafe { ResumeThread(main_thread.as_raw_handle() as _) } == DWORD::MAX
+            {
+                let err = IoError::last_os_error();
+                unsafe { TerminateJobObject(job.as_raw_handle() as _, 1) };
+                return Err(Error::new(err)
+                    .context("failed to resume the child after assigning it to a job"));
+            }
+            Some(job)
+        } else {
+            None
+        };
+
         Ok(WinChild {
             proc: Mutex::new(proc),
+            pid: pi.dwProcessId,
+            job,
+            input: None,
+            stderr: stderr_reader,
+            main_thread: if cmd.suspended || cmd.retain_main_thread {
+                Some(main_thread)
+            } else {
+                None
+            },
+            suspended: cmd.suspended,
+            terminated: Default::default(),
         })
     }
+
+    /// Sets the code page of the console by running `chcp` in it, as we
+    /// aren't attached to the pseudoconsole and so can't call
+    /// `SetConsoleCP` ourselves
+    fn set_code_page(&self, code_page: u32) -> anyhow::Result<()> {
+        let mut cmd = CommandBuilder::new("chcp.com");
+        cmd.arg(code_page.to_string());
+        cmd.stdout(StdioMode::Null);
+        cmd.stderr(StdioMode::Null);
+        let mut child = self.spawn_command(cmd)?;
+        let status = match child.wait_timeout(CHCP_TIMEOUT)? {
+            Some(status) => status,
+            None => {
+                child.kill().ok();
+                bail!(
+                    "timed out after {:?} setting the console code page to {}",
+                    CHCP_TIMEOUT,
+                    code_page
+                );
+            }
+        };
+        ensure!(
+            status.success(),
+            "failed to set the console code page to {}",
+            code_page
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,516 @@
+//! A pty implementation built on top of the winpty library.
+//! This allows running on versions of Windows that predate conpty.
+//! winpty.dll and winpty-agent.exe must be deployed alongside the
+//! application; the DLL is loaded at runtime.
+use crate::cmdbuilder::{CommandBuilder, StdioMode};
+use crate::win::job::create_kill_on_close_job;
+use crate::win::WinChild;
+use crate::{
+    box_child, Child, InitialInput, MasterPty, PtyError, PtyHandleSet, PtyPair, PtySize, PtySystem,
+    ReadableToken, SlavePty,
+};
+use anyhow::{anyhow, bail, ensure, Error};
+use filedescriptor::{FileDescriptor, OwnedHandle};
+use lazy_static::lazy_static;
+use shared_library::shared_library;
+use std::ffi::OsString;
+use std::io::{self, Error as IoError, Read, Write};
+use std::os::windows::ffi::OsStringExt;
+use std::os::windows::io::{AsRawHandle, FromRawHandle, RawHandle};
+use std::path::Path;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use winapi::shared::minwindef::{BOOL, DWORD};
+use winapi::um::fileapi::{CreateFileW, OPEN_EXISTING};
+use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+use winapi::um::processthreadsapi::GetProcessId;
+use winapi::um::winnt::{GENERIC_READ, GENERIC_WRITE, HANDLE, LPCWSTR};
+
+#[allow(non_camel_case_types)]
+pub enum winpty_config_t {}
+#[allow(non_camel_case_types)]
+pub enum winpty_t {}
+#[allow(non_camel_case_types)]
+pub enum winpty_spawn_config_t {}
+#[allow(non_camel_case_types)]
+pub enum winpty_error_t {}
+#[allow(non_camel_case_types)]
+type winpty_error_ptr_t = *mut winpty_error_t;
+
+const WINPTY_FLAG_COLOR_ESCAPES: u64 = 0x4;
+const WINPTY_SPAWN_FLAG_AUTO_SHUTDOWN: u64 = 0x1;
+
+shared_library!(WinPtyFuncs,
+    pub fn winpty_error_msg(err: winpty_error_ptr_t) -> LPCWSTR,
+    pub fn winpty_error_free(err: winpty_error_ptr_t),
+    pub fn winpty_config_new(
+        agentFlags: u64,
+        err: *mut winpty_error_ptr_t
+    ) -> *mut winpty_config_t,
+    pub fn winpty_config_free(cfg: *mut winpty_config_t),
+    pub fn winpty_config_set_initial_size(cfg: *mut winpty_config_t, cols: i32, rows: i32),
+    pub fn winpty_open(
+        cfg: *const winpty_config_t,
+        err: *mut winpty_error_ptr_t
+    ) -> *mut winpty_t,
+    pub fn winpty_conin_name(wp: *mut winpty_t) -> LPCWSTR,
+    pub fn winpty_conout_name(wp: *mut winpty_t) -> LPCWSTR,
+    pub fn winpty_spawn_config_new(
+        spawnFlags: u64,
+        appname: LPCWSTR,
+        cmdline: LPCWSTR,
+        cwd: LPCWSTR,
+        env: LPCWSTR,
+        err: *mut winpty_error_ptr_t
+    ) -> *mut winpty_spawn_config_t,
+    pub fn winpty_spawn_config_free(cfg: *mut winpty_spawn_config_t),
+    pub fn winpty_spawn(
+        wp: *mut winpty_t,
+        cfg: *const winpty_spawn_config_t,
+        process_handle: *mut HANDLE,
+        thread_handle: *mut HANDLE,
+        create_process_error: *mut DWORD,
+        err: *mut winpty_error_ptr_t
+    ) -> BOOL,
+    pub fn winpty_set_size(
+        wp: *mut winpty_t,
+        cols: i32,
+        rows: i32,
+        err: *mut winpty_error_ptr_t
+    ) -> BOOL,
+    pub fn winpty_free(wp: *mut winpty_t),
+);
+
+fn load_winpty() -> Option<WinPtyFuncs> {
+    match WinPtyFuncs::open(Path::new("winpty.dll")) {
+        Ok(funcs) => Some(funcs),
+        Err(err) => {
+            log::error!("failed to load winpty.dll: {:?}", err);
+            None
+        }
+    }
+}
+
+lazy_static! {
+    static ref WINPTY: Option<WinPtyFuncs> = load_winpty();
+}
+
+fn winpty() -> Result<&'static WinPtyFuncs, Error> {
+    WINPTY
+        .as_ref()
+        .ok_or_else(|| anyhow!("winpty is not available: winpty.dll could not be loaded"))
+}
+
+/// Returns true if winpty.dll could be loaded
+pub fn winpty_is_available() -> bool {
+    WINPTY.is_some()
+}
+
+/// Converts a nul terminated wide string returned by winpty
+fn wide_to_string(s: LPCWSTR) -> String {
+    if s.is_null() {
+        return String::new();
+    }
+    let len = (0..).take_while(|&i| unsafe { *s.offset(i) } != 0).count();
+    let slice = unsafe { std::slice::from_raw_parts(s, len) };
+    OsString::from_wide(slice).to_string_lossy().into_owned()
+}
+
+/// Consumes a winpty error, returning its message
+fn take_error(funcs: &WinPtyFuncs, err: winpty_error_ptr_t) -> String {
+    if err.is_null() {
+        return "unknown winpty error".to_string();
+    }
+    let msg = wide_to_string(unsafe { (funcs.winpty_error_msg)(err) });
+    unsafe { (funcs.winpty_error_free)(err) };
+    msg
+}
+
+/// Opens one of the named pipes that winpty exposes for conin/conout
+fn open_pipe(name: LPCWSTR, access: DWORD) -> Result<FileDescriptor, Error> {
+    let handle = unsafe {
+        CreateFileW(
+            name,
+            access,
+            0,
+            ptr::null_mut(),
+            OPEN_EXISTING,
+            0,
+            ptr::null_mut(),
+        )
+    };
+    ensure!(
+        handle != INVALID_HANDLE_VALUE,
+        "failed to open winpty pipe {}: {}",
+        wide_to_string(name),
+        IoError::last_os_error()
+    );
+    Ok(unsafe { FileDescriptor::from_raw_handle(handle as _) })
+}
+
+struct WinPty {
+    pty: *mut winpty_t,
+}
+
+unsafe impl Send for WinPty {}
+unsafe impl Sync for WinPty {}
+
+impl Drop for WinPty {
+    fn drop(&mut self) {
+        if let Some(funcs) = WINPTY.as_ref() {
+            unsafe { (funcs.winpty_free)(self.pty) };
+        }
+    }
+}
+
+impl WinPty {
+    fn open(size: PtySize) -> Result<Self, Error> {
+        let funcs = winpty()?;
+        let mut err: winpty_error_ptr_t = ptr::null_mut();
+
+        let cfg = unsafe { (funcs.winpty_config_new)(WINPTY_FLAG_COLOR_ESCAPES, &mut err) };
+        if cfg.is_null() {
+            bail!("winpty_config_new failed: {}", take_error(funcs, err));
+        }
+        unsafe { (funcs.winpty_config_set_initial_size)(cfg, size.cols as i32, size.rows as i32) };
+
+        let pty = unsafe { (funcs.winpty_open)(cfg, &mut err) };
+        unsafe { (funcs.winpty_config_free)(cfg) };
+        if pty.is_null() {
+            bail!("winpty_open failed: {}", take_error(funcs, err));
+        }
+
+        Ok(Self { pty })
+    }
+
+    fn resize(&self, size: PtySize) -> Result<(), IoError> {
+        let funcs =
+            winpty().map_err(|err| IoError::new(io::ErrorKind::Unsupported, err.to_string()))?;
+        let mut err: winpty_error_ptr_t = ptr::null_mut();
+        let res = unsafe {
+            (funcs.winpty_set_size)(self.pty, size.cols as i32, size.rows as i32, &mut err)
+        };
+        if res == 0 {
+            return Err(IoError::new(io::ErrorKind::Other, take_error(funcs, err)));
+        }
+        Ok(())
+    }
+
+    fn spawn_command(&self, cmd: CommandBuilder) -> anyhow::Result<WinChild> {
+        check_supported(&cmd)?;
+        let funcs = winpty()?;
+        let (exe, cmdline) = cmd.cmdline()?;
+        let cwd = cmd.current_directory()?;
+        let env = cmd.environment_block();
+        let mut err: winpty_error_ptr_t = ptr::null_mut();
+
+        let spawn_cfg = unsafe {
+            (funcs.winpty_spawn_config_new)(
+                WINPTY_SPAWN_FLAG_AUTO_SHUTDOWN,
+                exe.as_ptr(),
+                cmdline.as_ptr(),
+                cwd.as_ref()
+                    .map(|c| c.as_slice().as_ptr())
+                    .unwrap_or(ptr::null()),
+                env.as_ptr(),
+                &mut err,
+            )
+        };
+        if spawn_cfg.is_null() {
+            bail!("winpty_spawn_config_new failed: {}", take_error(funcs, err));
+        }
+
+        let mut proc: HANDLE = ptr::null_mut();
+        let mut create_process_error: DWORD = 0;
+        let res = unsafe {
+            (funcs.winpty_spawn)(
+                self.pty,
+                spawn_cfg,
+                &mut proc,
+                ptr::null_mut(),
+                &mut create_process_error,
+                &mut err,
+            )
+        };
+        unsafe { (funcs.winpty_spawn_config_free)(spawn_cfg) };
+
+        if res == 0 {
+            let msg = take_error(funcs, err);
+            log::error!(
+                "winpty_spawn `{:?}` in cwd `{:?}` failed: {}",
+                OsString::from_wide(&cmdline),
+                cwd.as_ref().map(|c| OsString::from_wide(c)),
+                msg
+            );
+            let source = if create_process_error != 0 {
+                IoError::from_raw_os_error(create_process_error as i32)
+            } else {
+                IoError::new(io::ErrorKind::Other, msg)
+            };
+            return Err(PtyError::Spawn {
+                command: format!("{:?}", OsString::from_wide(&cmdline)),
+                source,
+            }
+            .into());
+        }
+
+        let proc = unsafe { OwnedHandle::from_raw_handle(proc as _) };
+        let pid = unsafe { GetProcessId(proc.as_raw_handle() as _) };
+
+        // winpty doesn't allow us to start the process suspended, so
+        // there is a small window in which the child may start processes
+        // that won't be members of the job
+        let job = if cmd.kill_on_drop_tree {
+            Some(create_kill_on_close_job(&proc)?)
+        } else {
+            None
+        };
+
+        Ok(WinChild {
+            proc: Mutex::new(proc),
+            pid,
+            job,
+            input: None,
+            stderr: None,
+            main_thread: None,
+            suspended: false,
+            terminated: Default::default(),
+        })
+    }
+}
+
+/// winpty spawns the process itself, from its agent, so most of the
+/// options that affect how the process is created can't be honored.
+/// Rather than spawning a process that differs from the one that was
+/// asked for, which in the case of a restricted token would run with
+/// more privileges than intended, we refuse to spawn it at all.
+fn check_supported(cmd: &CommandBuilder) -> anyhow::Result<()> {
+    let unsupported = [
+        ("with_token", cmd.token.is_some()),
+        ("parent_process", cmd.parent_process.is_some()),
+        ("suspended", cmd.suspended),
+        ("retain_main_thread", cmd.retain_main_thread),
+        ("stdin", cmd.stdin != StdioMode::Pty),
+        ("stdout", cmd.stdout != StdioMode::Pty),
+        ("stderr", cmd.stderr != StdioMode::Pty),
+        ("priority", cmd.priority.is_some()),
+        ("new_process_group", cmd.new_process_group),
+        ("inherit_handle", !cmd.inherit_handles.is_empty()),
+        ("code_page", cmd.code_page.is_some()),
+    ];
+    for (option, set) in &unsupported {
+        ensure!(
+            !set,
+            "the winpty pty does not support CommandBuilder::{}",
+            option
+        );
+    }
+    Ok(())
+}
+
+/// A `PtySystem` implemented using winpty, for use on systems where
+/// conpty is not available.  Requires winpty.dll and winpty-agent.exe
+/// to be present alongside the application.
+#[derive(Default)]
+pub struct WinPtySystem {}
+
+impl PtySystem for WinPtySystem {
+    fn openpty(&self, size: PtySize) -> anyhow::Result<PtyPair> {
+        let pty = WinPty::open(size)?;
+        let funcs = winpty()?;
+        let writable = open_pipe(unsafe { (funcs.winpty_conin_name)(pty.pty) }, GENERIC_WRITE)?;
+        let readable = open_pipe(unsafe { (funcs.winpty_conout_name)(pty.pty) }, GENERIC_READ)?;
+
+        let master = WinPtyMasterPty {
+            reader: Arc::new(Mutex::new(readable.try_clone().map_err(
+                PtyError::handle_clone("winpty output pipe", "read from the master"),
+            )?)),
+            writer: Arc::new(Mutex::new(writable.try_clone().map_err(
+                PtyError::handle_clone("winpty input pipe", "write to the master"),
+            )?)),
+            inner: Arc::new(Mutex::new(Inner {
+                pty,
+                readable,
+                writable: Arc::new(writable),
+                size,
+            })),
+        };
+
+        let slave = WinPtySlavePty {
+            inner: master.inner.clone(),
+        };
+
+        Ok(PtyPair {
+            master: Box::new(master),
+            slave: Box::new(slave),
+        })
+    }
+}
+
+struct Inner {
+    pty: WinPty,
+    readable: FileDescriptor,
+    writable: Arc<FileDescriptor>,
+    size: PtySize,
+}
+
+#[derive(Clone)]
+pub struct WinPtyMasterPty {
+    inner: Arc<Mutex<Inner>>,
+    /// Used by `read_output`, separately from `inner` for the same
+    /// reason as `writer`
+    reader: Arc<Mutex<FileDescriptor>>,
+    /// Used by the Write impl, separately from `inner` so that a
+    /// blocked write doesn't prevent resizing or cloning readers.
+    writer: Arc<Mutex<FileDescriptor>>,
+}
+
+pub struct WinPtySlavePty {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl MasterPty for WinPtyMasterPty {
+    fn resize(&self, size: PtySize) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .pty
+            .resize(size)
+            .map_err(|source| PtyError::Resize { size, source })?;
+        inner.size = size;
+        Ok(())
+    }
+
+    fn get_size(&self) -> Result<PtySize, Error> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.size)
+    }
+
+    fn try_clone_reader(&self) -> anyhow::Result<Box<dyn std::io::Read + Send>> {
+        Ok(Box::new(
+            self.inner
+                .lock()
+                .unwrap()
+                .readable
+                .try_clone()
+                .map_err(PtyError::handle_clone(
+                    "winpty output pipe",
+                    "create a reader",
+                ))?,
+        ))
+    }
+
+    fn try_clone_writer(&self) -> anyhow::Result<Box<dyn std::io::Write + Send>> {
+        Ok(Box::new(
+            self.inner
+                .lock()
+                .unwrap()
+                .writable
+                .try_clone()
+                .map_err(PtyError::handle_clone(
+                    "winpty input pipe",
+                    "create a writer",
+                ))?,
+        ))
+    }
+
+    fn read_output(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.lock().unwrap().read(buf)
+    }
+
+    fn read_available(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let mut reader = self.reader.lock().unwrap();
+        Ok(crate::win::read_available(&mut reader, buf)?)
+    }
+
+    fn readable_token(&self) -> anyhow::Result<ReadableToken> {
+        let reader = self.reader.lock().unwrap();
+        Ok(ReadableToken::new(reader.try_clone().map_err(
+            PtyError::handle_clone("winpty output pipe", "create a readable token"),
+        )?))
+    }
+
+    fn handle_set(&self) -> anyhow::Result<PtyHandleSet> {
+        let inner = self.inner.lock().unwrap();
+        Ok(PtyHandleSet {
+            read: inner
+                .readable
+                .try_clone()
+                .map_err(PtyError::handle_clone("winpty output pipe", "share it"))?,
+            write: inner
+                .writable
+                .try_clone()
+                .map_err(PtyError::handle_clone("winpty input pipe", "share it"))?,
+        })
+    }
+
+    fn as_raw_handles(&self) -> Option<(RawHandle, RawHandle)> {
+        let inner = self.inner.lock().unwrap();
+        Some((
+            inner.readable.as_raw_handle(),
+            inner.writable.as_raw_handle(),
+        ))
+    }
+
+    fn send_eof(&self) -> anyhow::Result<()> {
+        self.write_all_pty(b"\x1a\r")
+    }
+}
+
+impl io::Write for WinPtyMasterPty {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.writer.lock().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+impl SlavePty for WinPtySlavePty {
+    fn spawn_command(
+        &self,
+        mut cmd: CommandBuilder,
+    ) -> anyhow::Result<Box<dyn Child + Send + Sync>> {
+        let inner = self.inner.lock().unwrap();
+        let kill_on_drop = cmd.kill_on_drop;
+        let initial_input = if cmd.initial_input.is_empty() {
+            None
+        } else {
+            let writer = inner.writable.try_clone().map_err(PtyError::handle_clone(
+                "winpty input pipe",
+                "write the initial input",
+            ))?;
+            Some(InitialInput::new(
+                std::mem::take(&mut cmd.initial_input),
+                writer,
+            ))
+        };
+        let mut child = inner.pty.spawn_command(cmd)?;
+        child.input = Some(Arc::downgrade(&inner.writable));
+        drop(inner);
+        let mut child = box_child(child, kill_on_drop);
+        if let Some(initial_input) = initial_input {
+            initial_input.write(&mut *child)?;
+        }
+        Ok(child)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_unsupported_options() {
+        assert!(check_supported(&CommandBuilder::new("cmd.exe")).is_ok());
+
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.with_token(0x1234 as _);
+        let err = check_supported(&cmd).unwrap_err();
+        assert!(err.to_string().contains("with_token"), "error was {}", err);
+
+        let mut cmd = CommandBuilder::new("cmd.exe");
+        cmd.stderr(StdioMode::Pipe);
+        let err = check_supported(&cmd).unwrap_err();
+        assert!(err.to_string().contains("stderr"), "error was {}", err);
+    }
+}